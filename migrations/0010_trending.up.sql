@@ -0,0 +1,76 @@
+-- Timestamps on activity tables, so popularity can decay over time.
+-- Existing rows get the migration time, as we have no better guess.
+
+ALTER TABLE kueater.liked_item ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE kueater.disliked_item ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE kueater.saved_item ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE kueater.liked_stall ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE kueater.saved_stall ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT NOW();
+
+CREATE INDEX IF NOT EXISTS liked_item_created_idx ON kueater.liked_item (created_at);
+CREATE INDEX IF NOT EXISTS disliked_item_created_idx ON kueater.disliked_item (created_at);
+CREATE INDEX IF NOT EXISTS saved_item_created_idx ON kueater.saved_item (created_at);
+CREATE INDEX IF NOT EXISTS review_created_idx ON kueater.review (created);
+
+-- Trending menu ranking
+-- Every like, save, dislike and review of the item's stall is an event,
+-- weighted by its kind and decayed exponentially by its age:
+--      weight * 2 ^ (-age_hours / half_life_hours)
+-- p_window limits which events count: 'today', 'week' or anything else for all time.
+CREATE OR REPLACE FUNCTION kueater.trending_menu(
+    p_window TEXT DEFAULT 'all',
+    p_limit INTEGER DEFAULT 40,
+    p_half_life_hours DOUBLE PRECISION DEFAULT 72
+)
+RETURNS TABLE (
+    menu_id UUID,
+    score DOUBLE PRECISION,
+    likes INTEGER,     -- raw counts inside the window, used for the ranking reason
+    saves INTEGER,
+    dislikes INTEGER,
+    reviews INTEGER
+)
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    since TIMESTAMPTZ;
+BEGIN
+    since := CASE p_window
+        -- Start of today in campus time
+        WHEN 'today' THEN date_trunc('day', NOW() AT TIME ZONE 'Asia/Bangkok') AT TIME ZONE 'Asia/Bangkok'
+        WHEN 'week' THEN NOW() - INTERVAL '7 days'
+        ELSE '-infinity'::TIMESTAMPTZ
+    END;
+
+    RETURN QUERY
+    WITH events AS (
+        SELECT li.menu_id, 'like' AS kind, 1.0::DOUBLE PRECISION AS weight, li.created_at
+        FROM kueater.liked_item li WHERE li.created_at >= since
+        UNION ALL
+        SELECT si.menu_id, 'save', 1.5, si.created_at
+        FROM kueater.saved_item si WHERE si.created_at >= since
+        UNION ALL
+        SELECT di.menu_id, 'dislike', -1.0, di.created_at
+        FROM kueater.disliked_item di WHERE di.created_at >= since
+        UNION ALL
+        -- Reviews are per stall, so every item of the stall shares a smaller part of it
+        SELECT sm.menu_id, 'review', 0.25 * (r.score - 2.5) / 2.5, r.created
+        FROM kueater.review r
+        JOIN kueater.stall_menu sm ON sm.stall_id = r.stall
+        WHERE r.created >= since
+    )
+    SELECT
+        e.menu_id,
+        SUM(
+            e.weight * POWER(2, -EXTRACT(EPOCH FROM (NOW() - e.created_at)) / 3600 / p_half_life_hours)
+        )::DOUBLE PRECISION,
+        COUNT(*) FILTER (WHERE e.kind = 'like')::INTEGER,
+        COUNT(*) FILTER (WHERE e.kind = 'save')::INTEGER,
+        COUNT(*) FILTER (WHERE e.kind = 'dislike')::INTEGER,
+        COUNT(*) FILTER (WHERE e.kind = 'review')::INTEGER
+    FROM events e
+    GROUP BY e.menu_id
+    ORDER BY 2 DESC, 1
+    LIMIT p_limit;
+END;
+$$;
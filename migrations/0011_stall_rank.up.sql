@@ -0,0 +1,300 @@
+-- Precomputed stall ranking, refreshed in the background by the server
+-- so every stall query reports the same rank.
+
+CREATE TABLE IF NOT EXISTS kueater.stall_rank (
+    stall_id UUID PRIMARY KEY REFERENCES kueater.stall ON DELETE CASCADE,
+    rank INTEGER NOT NULL,
+    score DOUBLE PRECISION NOT NULL,
+    bayes_rating DOUBLE PRECISION NOT NULL,     -- review average smoothed toward the global average
+    refreshed_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- Stall ranking algorithm
+--      score = like_weight * likes + review_weight * reviews + rating_weight * bayes_rating
+--      bayes_rating = (prior_weight * global_avg + sum_of_scores) / (prior_weight + reviews)
+-- A stall with few reviews is pulled toward the average of all reviews,
+-- so a single five-star review cannot outrank many four-star ones.
+CREATE OR REPLACE FUNCTION kueater.refresh_stall_rank(
+    p_like_weight DOUBLE PRECISION DEFAULT 0.4,
+    p_review_weight DOUBLE PRECISION DEFAULT 0.3,
+    p_rating_weight DOUBLE PRECISION DEFAULT 0.3,
+    p_prior_weight DOUBLE PRECISION DEFAULT 5
+)
+RETURNS VOID
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    global_avg DOUBLE PRECISION;
+BEGIN
+    SELECT COALESCE(AVG(score), 3)::DOUBLE PRECISION INTO global_avg
+    FROM kueater.review;
+
+    WITH stall_reviews AS (
+        SELECT
+            stall,
+            COUNT(*) AS review_count,
+            SUM(score) AS score_sum
+        FROM kueater.review
+        GROUP BY stall
+    ),
+    stall_likes AS (
+        SELECT
+            stall_id,
+            COUNT(*) AS like_count
+        FROM kueater.liked_stall
+        GROUP BY stall_id
+    ),
+    scored AS (
+        SELECT
+            s.id,
+            (p_prior_weight * global_avg + COALESCE(sr.score_sum, 0))
+                / (p_prior_weight + COALESCE(sr.review_count, 0)) AS bayes_rating,
+            COALESCE(sl.like_count, 0) AS like_count,
+            COALESCE(sr.review_count, 0) AS review_count
+        FROM kueater.stall s
+        LEFT JOIN stall_reviews sr ON s.id = sr.stall
+        LEFT JOIN stall_likes sl ON s.id = sl.stall_id
+    ),
+    ranked AS (
+        SELECT
+            id,
+            bayes_rating,
+            p_like_weight * like_count + p_review_weight * review_count + p_rating_weight * bayes_rating AS score
+        FROM scored
+    )
+    INSERT INTO kueater.stall_rank (stall_id, rank, score, bayes_rating, refreshed_at)
+    SELECT
+        id,
+        ROW_NUMBER() OVER (ORDER BY score DESC, id)::INTEGER,
+        score,
+        bayes_rating,
+        NOW()
+    FROM ranked
+    ON CONFLICT (stall_id) DO UPDATE
+    SET rank = EXCLUDED.rank,
+        score = EXCLUDED.score,
+        bayes_rating = EXCLUDED.bayes_rating,
+        refreshed_at = EXCLUDED.refreshed_at;
+END;
+$$;
+
+-- Fill once so ranks exist before the first background refresh
+SELECT kueater.refresh_stall_rank();
+
+-- Stall props now read their rank from kueater.stall_rank
+CREATE OR REPLACE FUNCTION kueater.get_stall_data_props(
+    p_stall_id UUID,
+    p_user_id UUID
+)
+RETURNS TABLE (
+    uuid TEXT,                 -- stall ID
+    rank INT4,                 -- rank position as int32
+    name TEXT,                 -- stall name
+    image_url TEXT,            -- stall image
+    location TEXT,
+    operating_hours TEXT,      -- Combining open_hour and close_hour
+    price_range TEXT,          -- min - max format
+    tags TEXT,                 -- stall tags
+    reviews INT4,              -- count of reviews as int32
+    likes INT4,                -- count of likes as int32
+    rating FLOAT4,             -- average review score as float
+    saved BOOLEAN              -- whether the user has saved this stall
+) AS $$
+BEGIN
+    RETURN QUERY
+    WITH stall_reviews AS (
+        SELECT
+            stall,
+            COUNT(*)::INT4 AS review_count,
+            COALESCE(AVG(score), 0)::FLOAT4 AS avg_score
+        FROM
+            kueater.review
+        WHERE
+            stall = p_stall_id
+        GROUP BY
+            stall
+    ),
+    stall_likes AS (
+        SELECT
+            stall_id,
+            COUNT(*)::INT4 AS like_count
+        FROM
+            kueater.liked_stall
+        WHERE
+            stall_id = p_stall_id
+        GROUP BY
+            stall_id
+    ),
+    stall_price_ranges AS (
+        SELECT
+            sm.stall_id,
+            MIN(mi.price)::INT4 AS min_price,
+            MAX(mi.price)::INT4 AS max_price
+        FROM
+            kueater.stall_menu sm
+        JOIN
+            kueater.menuitem mi ON sm.menu_id = mi.id
+        WHERE
+            sm.stall_id = p_stall_id
+        GROUP BY
+            sm.stall_id
+    )
+
+    SELECT
+        s.id::TEXT AS uuid,
+        COALESCE(rk.rank, 0)::INT4 AS rank,
+        s.name AS name,
+        s.image AS image_url,
+        s.lock::TEXT AS location,  -- Not in original schema
+        CASE
+            WHEN s.open_hour IS NOT NULL AND s.close_hour IS NOT NULL THEN
+                s.open_hour || ' - ' || s.close_hour
+            ELSE NULL
+        END AS operating_hours,
+        CASE
+            WHEN spr.min_price = spr.max_price THEN spr.min_price::TEXT
+            WHEN spr.min_price IS NULL OR spr.max_price IS NULL THEN NULL
+            ELSE spr.min_price::TEXT || ' - ' || spr.max_price::TEXT
+        END AS price_range,
+        s.tags AS tags,
+        COALESCE(sr.review_count, 0)::INT4 AS reviews,
+        COALESCE(sl.like_count, 0)::INT4 AS likes,
+        COALESCE(sr.avg_score, 0)::FLOAT4 AS rating,
+        CASE
+            WHEN p_user_id IS NOT NULL THEN
+                EXISTS (
+                    SELECT 1 FROM kueater.saved_stall ss
+                    WHERE ss.stall_id = s.id AND ss.user_id = p_user_id
+                )
+            ELSE false
+        END AS saved
+    FROM
+        kueater.stall s
+    LEFT JOIN
+        stall_reviews sr ON s.id = sr.stall
+    LEFT JOIN
+        stall_likes sl ON s.id = sl.stall_id
+    LEFT JOIN
+        stall_price_ranges spr ON s.id = spr.stall_id
+    LEFT JOIN
+        kueater.stall_rank rk ON s.id = rk.stall_id
+    WHERE
+        s.id = p_stall_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION kueater.multi_stall_data_props(
+    p_user_id UUID,  -- Optional user ID to check for saved status
+    p_limit INTEGER DEFAULT 20
+)
+RETURNS TABLE (
+    uuid TEXT,                 -- stall ID
+    rank INT4,                 -- rank position as int32
+    name TEXT,                 -- stall name
+    image_url TEXT,            -- stall image
+    location TEXT,             -- Not in original schema, leaving as NULL
+    operating_hours TEXT,      -- Combining open_hour and close_hour
+    price_range TEXT,          -- min - max format
+    tags TEXT,                 -- stall tags
+    reviews INT4,              -- count of reviews as int32
+    likes INT4,                -- count of likes as int32
+    rating FLOAT4,             -- average review score as float
+    saved BOOLEAN              -- whether the user has saved this stall
+) AS $$
+BEGIN
+    RETURN QUERY
+    WITH stall_reviews AS (
+        SELECT
+            stall,
+            COUNT(*)::INT4 AS review_count,
+            COALESCE(AVG(score), 0)::FLOAT4 AS avg_score
+        FROM
+            kueater.review
+        GROUP BY
+            stall
+    ),
+    stall_likes AS (
+        SELECT
+            stall_id,
+            COUNT(*)::INT4 AS like_count
+        FROM
+            kueater.liked_stall
+        GROUP BY
+            stall_id
+    ),
+    stall_price_ranges AS (
+        SELECT
+            sm.stall_id,
+            MIN(mi.price)::INT4 AS min_price,
+            MAX(mi.price)::INT4 AS max_price
+        FROM
+            kueater.stall_menu sm
+        JOIN
+            kueater.menuitem mi ON sm.menu_id = mi.id
+        GROUP BY
+            sm.stall_id
+    ),
+    stall_rank_data AS (
+        SELECT
+            s.id,
+            s.name,
+            s.image,
+            s.lock::TEXT AS location,
+            s.open_hour,
+            s.close_hour,
+            s.tags,
+            COALESCE(sr.review_count, 0)::INT4 AS review_count,
+            COALESCE(sl.like_count, 0)::INT4 AS like_count,
+            COALESCE(sr.avg_score, 0)::FLOAT4 AS avg_score,
+            COALESCE(spr.min_price, 0)::INT4 AS min_price,
+            COALESCE(spr.max_price, 0)::INT4 AS max_price,
+            rk.rank AS rank,
+            CASE
+                WHEN p_user_id IS NOT NULL THEN
+                    EXISTS (
+                        SELECT 1 FROM kueater.saved_stall ss
+                        WHERE ss.stall_id = s.id AND ss.user_id = p_user_id
+                    )
+                ELSE false
+            END AS is_saved
+        FROM
+            kueater.stall s
+        LEFT JOIN
+            stall_reviews sr ON s.id = sr.stall
+        LEFT JOIN
+            stall_likes sl ON s.id = sl.stall_id
+        LEFT JOIN
+            stall_price_ranges spr ON s.id = spr.stall_id
+        LEFT JOIN
+            kueater.stall_rank rk ON s.id = rk.stall_id
+    )
+
+    SELECT
+        srd.id::TEXT AS uuid,
+        COALESCE(srd.rank, 0)::INT4 AS rank,
+        srd.name AS name,
+        srd.image AS image_url,
+        srd.location AS location,  -- Not in original schema
+        CASE
+            WHEN srd.open_hour IS NOT NULL AND srd.close_hour IS NOT NULL THEN
+                srd.open_hour || ' - ' || srd.close_hour
+            ELSE NULL
+        END AS operating_hours,
+        CASE
+            WHEN srd.min_price = srd.max_price THEN srd.min_price::TEXT
+            WHEN srd.min_price = 0 AND srd.max_price = 0 THEN NULL
+            ELSE srd.min_price::TEXT || ' - ' || srd.max_price::TEXT
+        END AS price_range,
+        srd.tags AS tags,
+        srd.review_count AS reviews,
+        srd.like_count AS likes,
+        srd.avg_score AS rating,
+        srd.is_saved AS saved
+    FROM
+        stall_rank_data srd
+    -- Stalls created after the last refresh have no rank yet, put them last
+    ORDER BY
+        srd.rank NULLS LAST, srd.id
+    LIMIT p_limit;
+END;
+$$ LANGUAGE plpgsql;
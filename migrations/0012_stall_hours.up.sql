@@ -0,0 +1,397 @@
+-- Structured operating hours for stalls, replacing free text open_hour / close_hour.
+-- All times are campus local time (Asia/Bangkok).
+
+CREATE TABLE IF NOT EXISTS kueater.stall_hours (
+    stall_id UUID REFERENCES kueater.stall ON DELETE CASCADE,
+    weekday SMALLINT NOT NULL CHECK (weekday BETWEEN 1 AND 7),    -- ISO weekday, Monday = 1
+    open_time TIME NOT NULL,
+    close_time TIME NOT NULL,      -- Earlier than open_time when the range goes past midnight, equal when open 24 hours
+    PRIMARY KEY (stall_id, weekday, open_time)
+);
+
+DO $$ BEGIN
+    CREATE TYPE kueater.closure_kind AS ENUM (
+        'holiday', 'temporary'
+    );
+EXCEPTION
+    WHEN duplicate_object THEN null;
+END; $$;
+
+CREATE TABLE IF NOT EXISTS kueater.stall_closure (
+    id BIGSERIAL PRIMARY KEY,
+    stall_id UUID REFERENCES kueater.stall ON DELETE CASCADE,  -- NULL closes every stall, e.g. public holidays
+    kind kueater.closure_kind NOT NULL,
+    starts_at TIMESTAMPTZ NOT NULL,
+    ends_at TIMESTAMPTZ NOT NULL,
+    note TEXT
+);
+
+CREATE INDEX IF NOT EXISTS stall_closure_range_idx ON kueater.stall_closure (starts_at, ends_at);
+
+-- Carry over the old text hours where they are a valid HH:MM (or HH.MM), assuming every day.
+-- Anything else, like "25:00" or a typo, is left out and the stall has no schedule.
+INSERT INTO kueater.stall_hours (stall_id, weekday, open_time, close_time)
+SELECT
+    s.id,
+    d.weekday,
+    replace(btrim(s.open_hour), '.', ':')::TIME,
+    replace(btrim(s.close_hour), '.', ':')::TIME
+FROM kueater.stall s
+CROSS JOIN generate_series(1, 7) AS d(weekday)
+WHERE s.open_hour ~ '^\s*([01]?\d|2[0-3])[:.][0-5]\d\s*$'
+AND s.close_hour ~ '^\s*([01]?\d|2[0-3])[:.][0-5]\d\s*$'
+ON CONFLICT DO NOTHING;
+
+-- Whether a stall is open at p_at, minutes until it closes, and today's hours as text.
+CREATE OR REPLACE FUNCTION kueater.stall_open_status(
+    p_stall_id UUID,
+    p_at TIMESTAMPTZ DEFAULT NOW()
+)
+RETURNS TABLE (
+    is_open BOOLEAN,
+    closes_in_minutes INTEGER,     -- NULL when closed
+    today_hours TEXT               -- NULL when the stall has no schedule
+)
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    local_now TIMESTAMP;
+    today SMALLINT;
+    yesterday SMALLINT;
+    active_closure kueater.closure_kind;
+    closes_at TIMESTAMP;
+    next_closure TIMESTAMP;
+    hours_text TEXT;
+    has_schedule BOOLEAN;
+BEGIN
+    local_now := p_at AT TIME ZONE 'Asia/Bangkok';
+    today := EXTRACT(ISODOW FROM local_now)::SMALLINT;
+    yesterday := CASE WHEN today = 1 THEN 7 ELSE today - 1 END;
+
+    SELECT EXISTS (
+        SELECT 1 FROM kueater.stall_hours sh WHERE sh.stall_id = p_stall_id
+    ) INTO has_schedule;
+
+    SELECT string_agg(
+        CASE
+            WHEN sh.open_time = sh.close_time THEN 'Open 24 hours'
+            ELSE to_char(sh.open_time, 'HH24:MI') || ' - ' || to_char(sh.close_time, 'HH24:MI')
+        END,
+        ', ' ORDER BY sh.open_time
+    ) INTO hours_text
+    FROM kueater.stall_hours sh
+    WHERE sh.stall_id = p_stall_id AND sh.weekday = today;
+
+    IF has_schedule AND hours_text IS NULL THEN
+        hours_text := 'Closed today';
+    END IF;
+
+    -- Holidays and temporary closures override the weekly schedule
+    SELECT sc.kind INTO active_closure
+    FROM kueater.stall_closure sc
+    WHERE (sc.stall_id = p_stall_id OR sc.stall_id IS NULL)
+    AND p_at >= sc.starts_at AND p_at < sc.ends_at
+    ORDER BY sc.stall_id NULLS LAST
+    LIMIT 1;
+
+    IF active_closure IS NOT NULL THEN
+        RETURN QUERY SELECT
+            FALSE,
+            NULL::INTEGER,
+            CASE active_closure
+                WHEN 'holiday' THEN 'Closed for holiday'
+                ELSE 'Temporarily closed'
+            END;
+        RETURN;
+    END IF;
+
+    -- Latest closing time among ranges covering now, including ranges from yesterday past midnight.
+    -- Equal open and close times are a 24 hour range, ending the next day.
+    SELECT MAX(ranges.closes) INTO closes_at
+    FROM (
+        SELECT local_now::DATE + sh.close_time AS closes
+        FROM kueater.stall_hours sh
+        WHERE sh.stall_id = p_stall_id AND sh.weekday = today
+        AND sh.open_time < sh.close_time
+        AND local_now::TIME >= sh.open_time AND local_now::TIME < sh.close_time
+        UNION ALL
+        SELECT local_now::DATE + 1 + sh.close_time
+        FROM kueater.stall_hours sh
+        WHERE sh.stall_id = p_stall_id AND sh.weekday = today
+        AND sh.open_time >= sh.close_time
+        AND local_now::TIME >= sh.open_time
+        UNION ALL
+        SELECT local_now::DATE + sh.close_time
+        FROM kueater.stall_hours sh
+        WHERE sh.stall_id = p_stall_id AND sh.weekday = yesterday
+        AND sh.open_time >= sh.close_time
+        AND local_now::TIME < sh.close_time
+    ) ranges;
+
+    IF closes_at IS NULL THEN
+        RETURN QUERY SELECT FALSE, NULL::INTEGER, hours_text;
+        RETURN;
+    END IF;
+
+    -- A closure starting before the range ends cuts it short
+    SELECT MIN(sc.starts_at AT TIME ZONE 'Asia/Bangkok') INTO next_closure
+    FROM kueater.stall_closure sc
+    WHERE (sc.stall_id = p_stall_id OR sc.stall_id IS NULL)
+    AND sc.starts_at > p_at;
+
+    IF next_closure IS NOT NULL AND next_closure < closes_at THEN
+        closes_at := next_closure;
+    END IF;
+
+    RETURN QUERY SELECT
+        TRUE,
+        CEIL(EXTRACT(EPOCH FROM (closes_at - local_now)) / 60)::INTEGER,
+        hours_text;
+END;
+$$;
+
+-- Stall props gain open status, computed from the schedule above.
+-- Return types change, so the old functions have to go first.
+DROP FUNCTION IF EXISTS kueater.get_stall_data_props(UUID, UUID);
+DROP FUNCTION IF EXISTS kueater.multi_stall_data_props(UUID, INTEGER);
+
+CREATE OR REPLACE FUNCTION kueater.get_stall_data_props(
+    p_stall_id UUID,
+    p_user_id UUID
+)
+RETURNS TABLE (
+    uuid TEXT,                 -- stall ID
+    rank INT4,                 -- rank position as int32
+    name TEXT,                 -- stall name
+    image_url TEXT,            -- stall image
+    location TEXT,
+    operating_hours TEXT,      -- Today's hours from the schedule
+    price_range TEXT,          -- min - max format
+    tags TEXT,                 -- stall tags
+    reviews INT4,              -- count of reviews as int32
+    likes INT4,                -- count of likes as int32
+    rating FLOAT4,             -- average review score as float
+    saved BOOLEAN,             -- whether the user has saved this stall
+    is_open_now BOOLEAN,
+    closes_in_minutes INT4     -- NULL when closed
+) AS $$
+BEGIN
+    RETURN QUERY
+    WITH stall_reviews AS (
+        SELECT
+            stall,
+            COUNT(*)::INT4 AS review_count,
+            COALESCE(AVG(score), 0)::FLOAT4 AS avg_score
+        FROM
+            kueater.review
+        WHERE
+            stall = p_stall_id
+        GROUP BY
+            stall
+    ),
+    stall_likes AS (
+        SELECT
+            stall_id,
+            COUNT(*)::INT4 AS like_count
+        FROM
+            kueater.liked_stall
+        WHERE
+            stall_id = p_stall_id
+        GROUP BY
+            stall_id
+    ),
+    stall_price_ranges AS (
+        SELECT
+            sm.stall_id,
+            MIN(mi.price)::INT4 AS min_price,
+            MAX(mi.price)::INT4 AS max_price
+        FROM
+            kueater.stall_menu sm
+        JOIN
+            kueater.menuitem mi ON sm.menu_id = mi.id
+        WHERE
+            sm.stall_id = p_stall_id
+        GROUP BY
+            sm.stall_id
+    )
+
+    SELECT
+        s.id::TEXT AS uuid,
+        COALESCE(rk.rank, 0)::INT4 AS rank,
+        s.name AS name,
+        s.image AS image_url,
+        s.lock::TEXT AS location,  -- Not in original schema
+        COALESCE(
+            os.today_hours,
+            CASE
+                WHEN s.open_hour IS NOT NULL AND s.close_hour IS NOT NULL THEN
+                    s.open_hour || ' - ' || s.close_hour
+                ELSE NULL
+            END
+        ) AS operating_hours,
+        CASE
+            WHEN spr.min_price = spr.max_price THEN spr.min_price::TEXT
+            WHEN spr.min_price IS NULL OR spr.max_price IS NULL THEN NULL
+            ELSE spr.min_price::TEXT || ' - ' || spr.max_price::TEXT
+        END AS price_range,
+        s.tags AS tags,
+        COALESCE(sr.review_count, 0)::INT4 AS reviews,
+        COALESCE(sl.like_count, 0)::INT4 AS likes,
+        COALESCE(sr.avg_score, 0)::FLOAT4 AS rating,
+        CASE
+            WHEN p_user_id IS NOT NULL THEN
+                EXISTS (
+                    SELECT 1 FROM kueater.saved_stall ss
+                    WHERE ss.stall_id = s.id AND ss.user_id = p_user_id
+                )
+            ELSE false
+        END AS saved,
+        os.is_open AS is_open_now,
+        os.closes_in_minutes::INT4 AS closes_in_minutes
+    FROM
+        kueater.stall s
+    LEFT JOIN
+        stall_reviews sr ON s.id = sr.stall
+    LEFT JOIN
+        stall_likes sl ON s.id = sl.stall_id
+    LEFT JOIN
+        stall_price_ranges spr ON s.id = spr.stall_id
+    LEFT JOIN
+        kueater.stall_rank rk ON s.id = rk.stall_id
+    CROSS JOIN LATERAL
+        kueater.stall_open_status(s.id) os
+    WHERE
+        s.id = p_stall_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION kueater.multi_stall_data_props(
+    p_user_id UUID,  -- Optional user ID to check for saved status
+    p_limit INTEGER DEFAULT 20,
+    p_open_only BOOLEAN DEFAULT FALSE
+)
+RETURNS TABLE (
+    uuid TEXT,                 -- stall ID
+    rank INT4,                 -- rank position as int32
+    name TEXT,                 -- stall name
+    image_url TEXT,            -- stall image
+    location TEXT,             -- Not in original schema, leaving as NULL
+    operating_hours TEXT,      -- Today's hours from the schedule
+    price_range TEXT,          -- min - max format
+    tags TEXT,                 -- stall tags
+    reviews INT4,              -- count of reviews as int32
+    likes INT4,                -- count of likes as int32
+    rating FLOAT4,             -- average review score as float
+    saved BOOLEAN,             -- whether the user has saved this stall
+    is_open_now BOOLEAN,
+    closes_in_minutes INT4     -- NULL when closed
+) AS $$
+BEGIN
+    RETURN QUERY
+    WITH stall_reviews AS (
+        SELECT
+            stall,
+            COUNT(*)::INT4 AS review_count,
+            COALESCE(AVG(score), 0)::FLOAT4 AS avg_score
+        FROM
+            kueater.review
+        GROUP BY
+            stall
+    ),
+    stall_likes AS (
+        SELECT
+            stall_id,
+            COUNT(*)::INT4 AS like_count
+        FROM
+            kueater.liked_stall
+        GROUP BY
+            stall_id
+    ),
+    stall_price_ranges AS (
+        SELECT
+            sm.stall_id,
+            MIN(mi.price)::INT4 AS min_price,
+            MAX(mi.price)::INT4 AS max_price
+        FROM
+            kueater.stall_menu sm
+        JOIN
+            kueater.menuitem mi ON sm.menu_id = mi.id
+        GROUP BY
+            sm.stall_id
+    ),
+    stall_rank_data AS (
+        SELECT
+            s.id,
+            s.name,
+            s.image,
+            s.lock::TEXT AS location,
+            s.open_hour,
+            s.close_hour,
+            s.tags,
+            COALESCE(sr.review_count, 0)::INT4 AS review_count,
+            COALESCE(sl.like_count, 0)::INT4 AS like_count,
+            COALESCE(sr.avg_score, 0)::FLOAT4 AS avg_score,
+            COALESCE(spr.min_price, 0)::INT4 AS min_price,
+            COALESCE(spr.max_price, 0)::INT4 AS max_price,
+            rk.rank AS rank,
+            os.is_open,
+            os.closes_in_minutes,
+            os.today_hours,
+            CASE
+                WHEN p_user_id IS NOT NULL THEN
+                    EXISTS (
+                        SELECT 1 FROM kueater.saved_stall ss
+                        WHERE ss.stall_id = s.id AND ss.user_id = p_user_id
+                    )
+                ELSE false
+            END AS is_saved
+        FROM
+            kueater.stall s
+        LEFT JOIN
+            stall_reviews sr ON s.id = sr.stall
+        LEFT JOIN
+            stall_likes sl ON s.id = sl.stall_id
+        LEFT JOIN
+            stall_price_ranges spr ON s.id = spr.stall_id
+        LEFT JOIN
+            kueater.stall_rank rk ON s.id = rk.stall_id
+        CROSS JOIN LATERAL
+            kueater.stall_open_status(s.id) os
+    )
+
+    SELECT
+        srd.id::TEXT AS uuid,
+        COALESCE(srd.rank, 0)::INT4 AS rank,
+        srd.name AS name,
+        srd.image AS image_url,
+        srd.location AS location,  -- Not in original schema
+        COALESCE(
+            srd.today_hours,
+            CASE
+                WHEN srd.open_hour IS NOT NULL AND srd.close_hour IS NOT NULL THEN
+                    srd.open_hour || ' - ' || srd.close_hour
+                ELSE NULL
+            END
+        ) AS operating_hours,
+        CASE
+            WHEN srd.min_price = srd.max_price THEN srd.min_price::TEXT
+            WHEN srd.min_price = 0 AND srd.max_price = 0 THEN NULL
+            ELSE srd.min_price::TEXT || ' - ' || srd.max_price::TEXT
+        END AS price_range,
+        srd.tags AS tags,
+        srd.review_count AS reviews,
+        srd.like_count AS likes,
+        srd.avg_score AS rating,
+        srd.is_saved AS saved,
+        srd.is_open AS is_open_now,
+        srd.closes_in_minutes::INT4 AS closes_in_minutes
+    FROM
+        stall_rank_data srd
+    WHERE
+        NOT p_open_only OR srd.is_open
+    -- Stalls created after the last refresh have no rank yet, put them last
+    ORDER BY
+        srd.rank NULLS LAST, srd.id
+    LIMIT p_limit;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,16 @@
+-- Canteens (or any building with food stalls), so stalls have a real place instead of just a lock number
+
+CREATE TABLE IF NOT EXISTS kueater.canteen (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v7(),
+    name TEXT NOT NULL,
+    building TEXT,
+    latitude DOUBLE PRECISION NOT NULL,
+    longitude DOUBLE PRECISION NOT NULL,
+    floors INTEGER NOT NULL DEFAULT 1
+);
+
+-- Stall keeps its lock number, now within a canteen
+ALTER TABLE kueater.stall ADD COLUMN IF NOT EXISTS canteen_id UUID REFERENCES kueater.canteen ON DELETE SET NULL;
+ALTER TABLE kueater.stall ADD COLUMN IF NOT EXISTS floor INTEGER;
+
+CREATE INDEX IF NOT EXISTS stall_canteen_idx ON kueater.stall (canteen_id);
@@ -0,0 +1,59 @@
+-- Structured search filters, applied inside SQL by both text and vector search.
+-- Every parameter is optional: NULL means "do not filter on this".
+
+CREATE INDEX IF NOT EXISTS menuitem_price_idx ON kueater.menuitem (price);
+CREATE INDEX IF NOT EXISTS menu_ingredient_ingredient_idx ON kueater.menu_ingredient (ingredient_id);
+
+-- (menu, stall) pairs passing every given filter
+--  p_exclude_allergens: drop items with any ingredient carrying one of these allergens
+--  p_diets: keep items whose every ingredient scores at least 0.5 for every given diet
+CREATE OR REPLACE FUNCTION kueater.filter_menuitems(
+    p_price_min DOUBLE PRECISION DEFAULT NULL,
+    p_price_max DOUBLE PRECISION DEFAULT NULL,
+    p_cuisines TEXT[] DEFAULT NULL,
+    p_food_types TEXT[] DEFAULT NULL,
+    p_exclude_allergens TEXT[] DEFAULT NULL,
+    p_diets TEXT[] DEFAULT NULL,
+    p_stall_ids UUID[] DEFAULT NULL,
+    p_canteen_ids UUID[] DEFAULT NULL
+)
+RETURNS TABLE (
+    menu_id UUID,
+    stall_id UUID
+)
+LANGUAGE sql STABLE
+AS $$
+    SELECT m.id, sm.stall_id
+    FROM kueater.menuitem m
+    JOIN kueater.stall_menu sm ON sm.menu_id = m.id
+    JOIN kueater.stall s ON s.id = sm.stall_id
+    WHERE (p_price_min IS NULL OR m.price >= p_price_min)
+    AND (p_price_max IS NULL OR m.price <= p_price_max)
+    AND (p_cuisines IS NULL OR lower(m.cuisine) IN (SELECT lower(c) FROM unnest(p_cuisines) c))
+    AND (p_food_types IS NULL OR lower(m.food_type) IN (SELECT lower(t) FROM unnest(p_food_types) t))
+    AND (p_stall_ids IS NULL OR sm.stall_id = ANY (p_stall_ids))
+    AND (p_canteen_ids IS NULL OR s.canteen_id = ANY (p_canteen_ids))
+    AND (p_exclude_allergens IS NULL OR NOT EXISTS (
+        SELECT 1
+        FROM kueater.menu_ingredient mi
+        JOIN kueater.ingredient_allergen_score ias ON ias.ingredient_id = mi.ingredient_id
+        WHERE mi.menu_id = m.id
+        AND lower(ias.allergen::TEXT) IN (SELECT lower(a) FROM unnest(p_exclude_allergens) a)
+        AND ias.score > 0
+    ))
+    AND (p_diets IS NULL OR (
+        -- Items without ingredient data cannot be vouched for
+        EXISTS (
+            SELECT 1 FROM kueater.menu_ingredient mi WHERE mi.menu_id = m.id
+        )
+        AND NOT EXISTS (
+            SELECT 1
+            FROM kueater.menu_ingredient mi
+            CROSS JOIN unnest(p_diets) AS d(diet)
+            LEFT JOIN kueater.ingredient_diet_score ids
+                ON ids.ingredient_id = mi.ingredient_id AND lower(ids.diet::TEXT) = lower(d.diet)
+            WHERE mi.menu_id = m.id
+            AND COALESCE(ids.score, 0) < 0.5
+        )
+    ))
+$$;
@@ -0,0 +1,216 @@
+-- Typo tolerant text search over menu items, stalls and ingredients.
+-- Trigram similarity catches spelling variants ("kraprao" / "krapao"),
+-- aliases cover names that share no letters at all ("กะเพรา").
+
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- Alternative names, transliterations and common misspellings of an object.
+-- Added by hand, or generated from kueater.search_variant below.
+CREATE TABLE IF NOT EXISTS kueater.search_alias (
+    object_type kueater.object_type NOT NULL,
+    object_id UUID NOT NULL,
+    alias TEXT NOT NULL,
+    generated BOOLEAN NOT NULL DEFAULT FALSE,     -- Replaced whenever the object's name changes
+    PRIMARY KEY (object_type, object_id, alias)
+);
+
+-- Spellings of the same word: Thai, its romanizations and English. An object whose name
+-- contains one term of a group gets every other term of the group as an alias, so
+-- "krapao" finds "ผัดกะเพราหมู" and "กะเพรา" finds "Pad Kraprao Moo".
+CREATE TABLE IF NOT EXISTS kueater.search_variant (
+    group_name TEXT NOT NULL,
+    term TEXT NOT NULL,
+    PRIMARY KEY (group_name, term)
+);
+
+-- 'simple' config: names are mixed Thai / English, no stemming is better than wrong stemming
+ALTER TABLE kueater.menuitem
+ADD COLUMN IF NOT EXISTS name_tsv TSVECTOR GENERATED ALWAYS AS (to_tsvector('simple', name)) STORED;
+ALTER TABLE kueater.stall
+ADD COLUMN IF NOT EXISTS name_tsv TSVECTOR GENERATED ALWAYS AS (to_tsvector('simple', name)) STORED;
+ALTER TABLE kueater.ingredient
+ADD COLUMN IF NOT EXISTS name_tsv TSVECTOR GENERATED ALWAYS AS (to_tsvector('simple', name)) STORED;
+
+CREATE INDEX IF NOT EXISTS menuitem_name_trgm_idx ON kueater.menuitem USING gin (name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS stall_name_trgm_idx ON kueater.stall USING gin (name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS ingredient_name_trgm_idx ON kueater.ingredient USING gin (name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS search_alias_trgm_idx ON kueater.search_alias USING gin (alias gin_trgm_ops);
+
+CREATE INDEX IF NOT EXISTS menuitem_name_tsv_idx ON kueater.menuitem USING gin (name_tsv);
+CREATE INDEX IF NOT EXISTS stall_name_tsv_idx ON kueater.stall USING gin (name_tsv);
+CREATE INDEX IF NOT EXISTS ingredient_name_tsv_idx ON kueater.ingredient USING gin (name_tsv);
+
+-- How well a name matches the query, between 0 and 1
+CREATE OR REPLACE FUNCTION kueater.name_match_score(
+    p_name TEXT,
+    p_tsv TSVECTOR,
+    p_query TEXT
+)
+RETURNS DOUBLE PRECISION
+LANGUAGE sql IMMUTABLE
+AS $$
+    SELECT GREATEST(
+        similarity(p_name, p_query),
+        word_similarity(p_query, p_name),
+        LEAST(ts_rank(p_tsv, plainto_tsquery('simple', p_query)) * 10, 1),
+        CASE WHEN p_name ILIKE '%' || p_query || '%' THEN 0.9 ELSE 0 END,
+        CASE WHEN lower(p_name) = lower(p_query) THEN 1 ELSE 0 END
+    )::DOUBLE PRECISION
+$$;
+
+-- Menu items matching the query by their own name or alias,
+-- their stall's name, or the name of an ingredient they contain.
+-- Matches through a stall or ingredient count for less than a direct hit.
+-- Returns every item once with its best score, best first.
+CREATE OR REPLACE FUNCTION kueater.text_search_menuitems(
+    p_query TEXT,
+    p_limit INT DEFAULT NULL
+)
+RETURNS TABLE (
+    menu_id UUID,
+    score DOUBLE PRECISION
+)
+LANGUAGE sql STABLE
+-- Default 0.6 misses single dropped letters in short words ("krapao" in "Pad Kraprao Moo")
+SET pg_trgm.word_similarity_threshold = 0.5
+AS $$
+    WITH menu_hits AS (
+        SELECT m.id AS menu_id, kueater.name_match_score(m.name, m.name_tsv, p_query) AS score
+        FROM kueater.menuitem m
+        WHERE m.name % p_query
+        OR p_query <% m.name
+        OR m.name_tsv @@ plainto_tsquery('simple', p_query)
+        OR m.name ILIKE '%' || p_query || '%'
+    ),
+    alias_hits AS (
+        SELECT a.object_type, a.object_id,
+        GREATEST(
+            similarity(a.alias, p_query),
+            word_similarity(p_query, a.alias),
+            CASE WHEN a.alias ILIKE '%' || p_query || '%' THEN 0.9 ELSE 0 END
+        )::DOUBLE PRECISION AS score
+        FROM kueater.search_alias a
+        WHERE a.alias % p_query
+        OR p_query <% a.alias
+        OR a.alias ILIKE '%' || p_query || '%'
+    ),
+    stall_hits AS (
+        SELECT s.id AS stall_id, kueater.name_match_score(s.name, s.name_tsv, p_query) AS score
+        FROM kueater.stall s
+        WHERE s.name % p_query
+        OR p_query <% s.name
+        OR s.name_tsv @@ plainto_tsquery('simple', p_query)
+        OR s.name ILIKE '%' || p_query || '%'
+        UNION ALL
+        SELECT object_id, score FROM alias_hits WHERE object_type = 'stall'
+    ),
+    ingredient_hits AS (
+        SELECT i.id AS ingredient_id, kueater.name_match_score(i.name, i.name_tsv, p_query) AS score
+        FROM kueater.ingredient i
+        WHERE i.name % p_query
+        OR p_query <% i.name
+        OR i.name_tsv @@ plainto_tsquery('simple', p_query)
+        OR i.name ILIKE '%' || p_query || '%'
+        UNION ALL
+        SELECT object_id, score FROM alias_hits WHERE object_type = 'ingredient'
+    ),
+    all_hits AS (
+        SELECT mh.menu_id, mh.score FROM menu_hits mh
+        UNION ALL
+        SELECT ah.object_id, ah.score FROM alias_hits ah WHERE ah.object_type = 'menuitem'
+        UNION ALL
+        SELECT sm.menu_id, sh.score * 0.6
+        FROM stall_hits sh
+        JOIN kueater.stall_menu sm ON sm.stall_id = sh.stall_id
+        UNION ALL
+        SELECT mi.menu_id, ih.score * 0.7
+        FROM ingredient_hits ih
+        JOIN kueater.menu_ingredient mi ON mi.ingredient_id = ih.ingredient_id
+    )
+    SELECT ah.menu_id, MAX(ah.score)
+    FROM all_hits ah
+    GROUP BY ah.menu_id
+    ORDER BY 2 DESC, 1
+    LIMIT p_limit
+$$;
+
+INSERT INTO kueater.search_variant (group_name, term) VALUES
+    ('kaphrao', 'กะเพรา'), ('kaphrao', 'กระเพรา'), ('kaphrao', 'kaphrao'), ('kaphrao', 'kraprao'),
+    ('kaphrao', 'krapao'), ('kaphrao', 'kapao'), ('kaphrao', 'gaprao'), ('kaphrao', 'kaprao'),
+    ('kaphrao', 'holy basil'),
+    ('phat thai', 'ผัดไทย'), ('phat thai', 'phat thai'), ('phat thai', 'pad thai'), ('phat thai', 'phad thai'),
+    ('phat si io', 'ผัดซีอิ๊ว'), ('phat si io', 'phat si io'), ('phat si io', 'pad see ew'), ('phat si io', 'pad see eiw'),
+    ('khao phat', 'ข้าวผัด'), ('khao phat', 'khao phat'), ('khao phat', 'khao pad'), ('khao phat', 'khao pat'),
+    ('khao phat', 'fried rice'),
+    ('khao man kai', 'ข้าวมันไก่'), ('khao man kai', 'khao man kai'), ('khao man kai', 'khao man gai'),
+    ('khao man kai', 'khao mun gai'), ('khao man kai', 'chicken rice'),
+    ('tom yam', 'ต้มยำ'), ('tom yam', 'tom yam'), ('tom yam', 'tom yum'),
+    ('som tam', 'ส้มตำ'), ('som tam', 'som tam'), ('som tam', 'som tum'), ('som tam', 'papaya salad'),
+    ('kuai tiao', 'ก๋วยเตี๋ยว'), ('kuai tiao', 'kuai tiao'), ('kuai tiao', 'kuay teow'),
+    ('kuai tiao', 'kway teow'), ('kuai tiao', 'noodle soup'),
+    ('khai chiao', 'ไข่เจียว'), ('khai chiao', 'khai chiao'), ('khai chiao', 'kai jeow'),
+    ('khai chiao', 'khai jiao'), ('khai chiao', 'thai omelette'),
+    ('mu krop', 'หมูกรอบ'), ('mu krop', 'mu krop'), ('mu krop', 'moo krob'), ('mu krop', 'crispy pork'),
+    ('mu', 'หมู'), ('mu', 'moo'), ('mu', 'pork'),
+    ('kai', 'ไก่'), ('kai', 'gai'), ('kai', 'chicken'),
+    ('kung', 'กุ้ง'), ('kung', 'goong'), ('kung', 'kung'), ('kung', 'shrimp')
+ON CONFLICT DO NOTHING;
+
+-- Whether the name contains the term as a word. Thai is written without spaces,
+-- so next to Thai letters any position is a word boundary.
+CREATE OR REPLACE FUNCTION kueater.name_has_term(p_name TEXT, p_term TEXT)
+RETURNS BOOLEAN
+LANGUAGE sql IMMUTABLE
+AS $$
+    SELECT lower(p_name) ~ ('(^|[^a-z0-9])' || regexp_replace(lower(p_term), '([^a-z0-9฀-๿ ])', '\\\1', 'g') || '($|[^a-z0-9])')
+$$;
+
+-- Regenerate the aliases of one object from its name
+CREATE OR REPLACE FUNCTION kueater.generate_search_aliases(
+    p_object_type kueater.object_type,
+    p_object_id UUID,
+    p_name TEXT
+)
+RETURNS VOID
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    DELETE FROM kueater.search_alias
+    WHERE object_type = p_object_type AND object_id = p_object_id AND generated;
+
+    INSERT INTO kueater.search_alias (object_type, object_id, alias, generated)
+    SELECT DISTINCT p_object_type, p_object_id, v.term, TRUE
+    FROM kueater.search_variant matched
+    JOIN kueater.search_variant v ON v.group_name = matched.group_name
+    WHERE kueater.name_has_term(p_name, matched.term)
+    AND NOT kueater.name_has_term(p_name, v.term)
+    ON CONFLICT DO NOTHING;
+END;
+$$;
+
+CREATE OR REPLACE FUNCTION kueater.search_aliases_on_name()
+RETURNS TRIGGER
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    PERFORM kueater.generate_search_aliases(TG_ARGV[0]::kueater.object_type, NEW.id, NEW.name);
+    RETURN NEW;
+END;
+$$;
+
+CREATE OR REPLACE TRIGGER menuitem_search_aliases
+AFTER INSERT OR UPDATE OF name ON kueater.menuitem
+FOR EACH ROW EXECUTE FUNCTION kueater.search_aliases_on_name('menuitem');
+
+CREATE OR REPLACE TRIGGER stall_search_aliases
+AFTER INSERT OR UPDATE OF name ON kueater.stall
+FOR EACH ROW EXECUTE FUNCTION kueater.search_aliases_on_name('stall');
+
+CREATE OR REPLACE TRIGGER ingredient_search_aliases
+AFTER INSERT OR UPDATE OF name ON kueater.ingredient
+FOR EACH ROW EXECUTE FUNCTION kueater.search_aliases_on_name('ingredient');
+
+-- Aliases for everything already in the catalog
+SELECT kueater.generate_search_aliases('menuitem', id, name) FROM kueater.menuitem;
+SELECT kueater.generate_search_aliases('stall', id, name) FROM kueater.stall;
+SELECT kueater.generate_search_aliases('ingredient', id, name) FROM kueater.ingredient;
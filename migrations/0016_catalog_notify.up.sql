@@ -0,0 +1,73 @@
+-- Tell listening backends that catalog data changed, so in-memory indexes can rebuild.
+-- Payload is the changed table's name, one notification per statement.
+
+CREATE OR REPLACE FUNCTION kueater.notify_catalog_change()
+RETURNS TRIGGER
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    PERFORM pg_notify('kueater_catalog', TG_TABLE_NAME);
+    RETURN NULL;
+END;
+$$;
+
+CREATE OR REPLACE TRIGGER menuitem_catalog_notify
+AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON kueater.menuitem
+FOR EACH STATEMENT EXECUTE FUNCTION kueater.notify_catalog_change();
+
+CREATE OR REPLACE TRIGGER stall_catalog_notify
+AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON kueater.stall
+FOR EACH STATEMENT EXECUTE FUNCTION kueater.notify_catalog_change();
+
+CREATE OR REPLACE TRIGGER ingredient_catalog_notify
+AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON kueater.ingredient
+FOR EACH STATEMENT EXECUTE FUNCTION kueater.notify_catalog_change();
+
+CREATE OR REPLACE TRIGGER stall_menu_catalog_notify
+AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON kueater.stall_menu
+FOR EACH STATEMENT EXECUTE FUNCTION kueater.notify_catalog_change();
+
+CREATE OR REPLACE TRIGGER menu_ingredient_catalog_notify
+AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON kueater.menu_ingredient
+FOR EACH STATEMENT EXECUTE FUNCTION kueater.notify_catalog_change();
+
+-- Every suggestion the prefix index serves, with popularity on one shared scale:
+-- likes and saves of the dish, or summed over the dishes behind a stall, cuisine or ingredient.
+CREATE OR REPLACE FUNCTION kueater.search_suggestions()
+RETURNS TABLE (
+    kind TEXT,
+    object_id UUID,     -- NULL for cuisines
+    label TEXT,
+    popularity DOUBLE PRECISION
+)
+LANGUAGE sql STABLE
+AS $$
+    WITH menu_pop AS (
+        SELECT m.id, m.name, m.cuisine,
+        (
+            (SELECT COUNT(*) FROM kueater.liked_item li WHERE li.menu_id = m.id)
+            + (SELECT COUNT(*) FROM kueater.saved_item si WHERE si.menu_id = m.id)
+        )::DOUBLE PRECISION AS pop
+        FROM kueater.menuitem m
+    )
+    SELECT 'menu', mp.id, mp.name, mp.pop
+    FROM menu_pop mp
+    UNION ALL
+    SELECT 'stall', s.id, s.name,
+    COALESCE(SUM(mp.pop), 0) + (SELECT COUNT(*) FROM kueater.liked_stall ls WHERE ls.stall_id = s.id)
+    FROM kueater.stall s
+    LEFT JOIN kueater.stall_menu sm ON sm.stall_id = s.id
+    LEFT JOIN menu_pop mp ON mp.id = sm.menu_id
+    GROUP BY s.id
+    UNION ALL
+    SELECT 'cuisine', NULL, MIN(mp.cuisine), SUM(mp.pop)
+    FROM menu_pop mp
+    WHERE mp.cuisine IS NOT NULL AND mp.cuisine <> ''
+    GROUP BY lower(mp.cuisine)
+    UNION ALL
+    SELECT 'ingredient', i.id, i.name, COALESCE(SUM(mp.pop), 0)
+    FROM kueater.ingredient i
+    LEFT JOIN kueater.menu_ingredient mi ON mi.ingredient_id = i.id
+    LEFT JOIN menu_pop mp ON mp.id = mi.menu_id
+    GROUP BY i.id
+$$;
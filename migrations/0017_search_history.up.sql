@@ -0,0 +1,77 @@
+-- Per-user search history, trending searches and zero-result reporting
+
+ALTER TABLE kueater.userprofile
+ADD COLUMN IF NOT EXISTS record_search_history BOOLEAN NOT NULL DEFAULT TRUE;
+
+CREATE TABLE IF NOT EXISTS kueater.search_history (
+    id BIGSERIAL PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES kueater.userprofile ON DELETE CASCADE,
+    query TEXT NOT NULL,            -- As typed
+    normalized TEXT NOT NULL,       -- Lowercased, whitespace collapsed, for grouping
+    result_count INT NOT NULL,
+    filtered BOOLEAN NOT NULL DEFAULT FALSE,   -- Filters, opening hours or allergens removed results
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS search_history_user_idx ON kueater.search_history (user_id, created_at DESC);
+CREATE INDEX IF NOT EXISTS search_history_created_idx ON kueater.search_history (created_at);
+
+-- Users allowed to read catalog reports
+CREATE TABLE IF NOT EXISTS kueater.admin_user (
+    user_id UUID PRIMARY KEY REFERENCES kueater.userprofile ON DELETE CASCADE,
+    granted_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Most searched queries in the last p_window_hours.
+-- Queries from fewer than p_min_users people are left out, so one user's searches never show up.
+CREATE OR REPLACE FUNCTION kueater.trending_searches(
+    p_window_hours INT DEFAULT 24,
+    p_limit INT DEFAULT 10,
+    p_min_users INT DEFAULT 2
+)
+RETURNS TABLE (
+    query TEXT,
+    searches BIGINT,
+    users BIGINT
+)
+LANGUAGE sql STABLE
+AS $$
+    SELECT
+    mode() WITHIN GROUP (ORDER BY sh.query),    -- Most common spelling
+    COUNT(*),
+    COUNT(DISTINCT sh.user_id)
+    FROM kueater.search_history sh
+    WHERE sh.created_at > NOW() - make_interval(hours => p_window_hours)
+    AND sh.result_count > 0
+    GROUP BY sh.normalized
+    HAVING COUNT(DISTINCT sh.user_id) >= p_min_users
+    ORDER BY 3 DESC, 2 DESC, 1
+    LIMIT p_limit
+$$;
+
+-- Queries that found nothing in the last p_window_hours, most requested first.
+-- Searches whose results were filtered away are left out, the catalogue may have the dish.
+CREATE OR REPLACE FUNCTION kueater.zero_result_searches(
+    p_window_hours INT DEFAULT 168,
+    p_limit INT DEFAULT 50
+)
+RETURNS TABLE (
+    query TEXT,
+    searches BIGINT,
+    users BIGINT,
+    last_searched TEXT
+)
+LANGUAGE sql STABLE
+AS $$
+    SELECT
+    mode() WITHIN GROUP (ORDER BY sh.query),
+    COUNT(*),
+    COUNT(DISTINCT sh.user_id),
+    MAX(sh.created_at)::TEXT
+    FROM kueater.search_history sh
+    WHERE sh.created_at > NOW() - make_interval(hours => p_window_hours)
+    AND sh.result_count = 0 AND NOT sh.filtered
+    GROUP BY sh.normalized
+    ORDER BY 3 DESC, 2 DESC, 1
+    LIMIT p_limit
+$$;
@@ -0,0 +1,62 @@
+-- Helpers for the "You may also like" section
+
+-- Whether the menu item has an ingredient carrying one of the user's allergens.
+-- Same rule as the allergen filter in kueater.filter_menuitems.
+CREATE OR REPLACE FUNCTION kueater.user_allergen_conflict(
+    p_user_id UUID,
+    p_menu_id UUID
+)
+RETURNS BOOLEAN
+LANGUAGE sql STABLE
+AS $$
+    SELECT EXISTS (
+        SELECT 1
+        FROM kueater.user_profile_preferences upp
+        JOIN kueater.user_preferences up ON up.id = upp.preferences_id
+        JOIN kueater.menu_ingredient mi ON mi.menu_id = p_menu_id
+        JOIN kueater.ingredient_allergen_score ias ON ias.ingredient_id = mi.ingredient_id
+        WHERE upp.user_id = p_user_id
+        AND ias.allergen = ANY (up.allergies)
+        AND ias.score > 0
+    )
+$$;
+
+-- Nearest menu items to a menu item's stored embeddings, by cosine distance.
+-- Items are only compared within the same embedding language; the closest language wins.
+-- p_exclude_disliked / p_exclude_allergens drop items the user disliked or is allergic to.
+CREATE OR REPLACE FUNCTION kueater.similar_menuitems(
+    p_menu_id UUID,
+    p_user_id UUID,
+    p_limit INT DEFAULT 10,
+    p_exclude_disliked BOOLEAN DEFAULT TRUE,
+    p_exclude_allergens BOOLEAN DEFAULT TRUE
+)
+RETURNS TABLE (
+    menu_id UUID,
+    distance DOUBLE PRECISION
+)
+LANGUAGE sql STABLE
+AS $$
+    SELECT n.object_id, MIN(n.distance)
+    FROM kueater.embeddings src
+    CROSS JOIN LATERAL (
+        -- Oversampled so exclusions below still leave enough items
+        SELECT e.object_id, (e.embedding <=> src.embedding)::DOUBLE PRECISION AS distance
+        FROM kueater.embeddings e
+        WHERE e.object_type = 'menuitem'
+        AND e.lang = src.lang
+        AND e.object_id <> src.object_id
+        ORDER BY e.embedding <=> src.embedding
+        LIMIT p_limit * 4
+    ) n
+    WHERE src.object_type = 'menuitem'
+    AND src.object_id = p_menu_id
+    AND NOT (p_exclude_disliked AND EXISTS (
+        SELECT 1 FROM kueater.disliked_item di
+        WHERE di.user_id = p_user_id AND di.menu_id = n.object_id
+    ))
+    AND NOT (p_exclude_allergens AND kueater.user_allergen_conflict(p_user_id, n.object_id))
+    GROUP BY n.object_id
+    ORDER BY 2, 1
+    LIMIT p_limit
+$$;
@@ -0,0 +1,114 @@
+-- Structured reasons on menu item scores, instead of matching words in the free-text reasoning.
+-- The agent may fill these itself; whatever it leaves NULL is derived from the user's
+-- preferences and the item's ingredients when the score is inserted.
+
+ALTER TABLE kueater.menuitem_scores
+ADD COLUMN IF NOT EXISTS matched_dish TEXT,            -- Favourite dish of the user this item matches
+ADD COLUMN IF NOT EXISTS matched_ingredient TEXT,      -- Favourite of the user found among the item's ingredients
+ADD COLUMN IF NOT EXISTS diet_conflict BOOLEAN,
+ADD COLUMN IF NOT EXISTS allergen_conflict BOOLEAN;    -- Includes traces, any allergen score above 0
+
+-- Reasons for one user and item, same rules as kueater.filter_menuitems and kueater.user_allergen_conflict
+CREATE OR REPLACE FUNCTION kueater.menuitem_score_reasons(
+    p_user_id UUID,
+    p_menu_id UUID
+)
+RETURNS TABLE (
+    matched_dish TEXT,
+    matched_ingredient TEXT,
+    diet_conflict BOOLEAN,
+    allergen_conflict BOOLEAN
+)
+LANGUAGE sql STABLE
+AS $$
+    WITH prefs AS (
+        SELECT up.diets, up.favorite_dishes
+        FROM kueater.user_profile_preferences upp
+        JOIN kueater.user_preferences up ON up.id = upp.preferences_id
+        WHERE upp.user_id = p_user_id
+    )
+    SELECT
+    (
+        SELECT fav
+        FROM prefs p, unnest(p.favorite_dishes) AS fav, kueater.menuitem m
+        WHERE m.id = p_menu_id AND word_similarity(fav, m.name) >= 0.6
+        ORDER BY word_similarity(fav, m.name) DESC, fav
+        LIMIT 1
+    ),
+    (
+        SELECT fav
+        FROM prefs p, unnest(p.favorite_dishes) AS fav
+        JOIN kueater.ingredient i ON lower(i.name) = lower(fav)
+        JOIN kueater.menu_ingredient mi ON mi.ingredient_id = i.id
+        WHERE mi.menu_id = p_menu_id
+        ORDER BY fav
+        LIMIT 1
+    ),
+    EXISTS (
+        SELECT 1
+        FROM prefs p
+        CROSS JOIN unnest(p.diets) AS d(diet)
+        JOIN kueater.menu_ingredient mi ON mi.menu_id = p_menu_id
+        LEFT JOIN kueater.ingredient_diet_score ids
+            ON ids.ingredient_id = mi.ingredient_id AND ids.diet = d.diet
+        WHERE COALESCE(ids.score, 0) < 0.5
+    )
+    -- Items without ingredient data cannot be vouched for
+    OR (
+        EXISTS (SELECT 1 FROM prefs p WHERE cardinality(p.diets) > 0)
+        AND NOT EXISTS (SELECT 1 FROM kueater.menu_ingredient mi WHERE mi.menu_id = p_menu_id)
+    ),
+    kueater.user_allergen_conflict(p_user_id, p_menu_id)
+$$;
+
+CREATE OR REPLACE FUNCTION kueater.fill_menuitem_score_reasons()
+RETURNS TRIGGER
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    derived RECORD;
+BEGIN
+    SELECT * INTO derived FROM kueater.menuitem_score_reasons(NEW.user_id, NEW.menu_id);
+    NEW.matched_dish := COALESCE(NEW.matched_dish, derived.matched_dish);
+    NEW.matched_ingredient := COALESCE(NEW.matched_ingredient, derived.matched_ingredient);
+    NEW.diet_conflict := COALESCE(NEW.diet_conflict, derived.diet_conflict);
+    NEW.allergen_conflict := COALESCE(NEW.allergen_conflict, derived.allergen_conflict);
+    RETURN NEW;
+END;
+$$;
+
+CREATE OR REPLACE TRIGGER menuitem_scores_reasons
+BEFORE INSERT ON kueater.menuitem_scores
+FOR EACH ROW EXECUTE FUNCTION kueater.fill_menuitem_score_reasons();
+
+-- Backfill current scores, stale ones are never read again
+UPDATE kueater.menuitem_scores ms
+SET matched_dish = r.matched_dish,
+    matched_ingredient = r.matched_ingredient,
+    diet_conflict = r.diet_conflict,
+    allergen_conflict = r.allergen_conflict
+FROM kueater.menuitem_scores src
+CROSS JOIN LATERAL kueater.menuitem_score_reasons(src.user_id, src.menu_id) r
+WHERE ms.id = src.id AND ms.stale = FALSE;
+
+-- Recreate the view with the new columns
+DROP MATERIALIZED VIEW IF EXISTS kueater.current_menuitem_scores;
+
+CREATE MATERIALIZED VIEW kueater.current_menuitem_scores AS
+SELECT
+    id,
+    user_id,
+    menu_id,
+    score,
+    reasoning,
+    matched_dish,
+    matched_ingredient,
+    COALESCE(diet_conflict, FALSE) AS diet_conflict,
+    COALESCE(allergen_conflict, FALSE) AS allergen_conflict,
+    created_at
+FROM kueater.menuitem_scores
+WHERE stale = FALSE
+ORDER BY score DESC;
+
+CREATE UNIQUE INDEX unique_current_menuitem_scores ON kueater.current_menuitem_scores (id);
+CREATE INDEX IF NOT EXISTS current_menuitem_scores_user_idx ON kueater.current_menuitem_scores (user_id, score DESC);
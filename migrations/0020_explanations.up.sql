@@ -0,0 +1,92 @@
+-- Structured explanation of a menu item for a user, rendered by the app as chips and warnings.
+-- Names are returned as stored, the app localizes the labels around them.
+
+-- Per diet of the user, the item's compatibility: its worst ingredient's score
+-- from kueater.get_menuitem_compatibility_score. Items without ingredient data get no diet rows.
+CREATE OR REPLACE FUNCTION kueater.explain_menuitem(
+    p_user_id UUID,
+    p_menu_id UUID
+)
+RETURNS TABLE (
+    cuisine TEXT,                   -- Set when the item's cuisine is one the user picked
+    similar_liked_dish TEXT,        -- A dish the user liked that this one resembles
+    disliked_ingredients TEXT[],    -- Ingredients the user listed as disliked
+    diets TEXT[],
+    diet_scores DOUBLE PRECISION[]  -- Same order as diets
+)
+LANGUAGE sql STABLE
+AS $$
+    WITH prefs AS (
+        SELECT up.diets, up.cuisines, up.disliked_ingredients
+        FROM kueater.user_profile_preferences upp
+        JOIN kueater.user_preferences up ON up.id = upp.preferences_id
+        WHERE upp.user_id = p_user_id
+    ),
+    compat AS (
+        SELECT * FROM kueater.get_menuitem_compatibility_score(p_menu_id)
+    ),
+    diet_compat AS (
+        SELECT d.diet::TEXT AS diet,
+        MIN(COALESCE((c.diet_scores ->> d.diet::TEXT)::DOUBLE PRECISION, 0)) AS score
+        FROM prefs p
+        CROSS JOIN unnest(p.diets) AS d(diet)
+        CROSS JOIN compat c
+        GROUP BY d.diet
+    )
+    SELECT
+    (
+        SELECT m.cuisine
+        FROM kueater.menuitem m, prefs p
+        WHERE m.id = p_menu_id
+        AND lower(m.cuisine) IN (SELECT lower(c) FROM unnest(p.cuisines) c)
+    ),
+    COALESCE(
+        (
+            SELECT cms.matched_dish
+            FROM kueater.current_menuitem_scores cms
+            WHERE cms.user_id = p_user_id AND cms.menu_id = p_menu_id
+            LIMIT 1
+        ),
+        (
+            -- Closest liked dish by stored embedding, if close enough to be worth saying
+            SELECT lm.name
+            FROM kueater.liked_item li
+            JOIN kueater.menuitem lm ON lm.id = li.menu_id
+            JOIN kueater.embeddings le ON le.object_id = li.menu_id AND le.object_type = 'menuitem'
+            JOIN kueater.embeddings te ON te.object_id = p_menu_id AND te.object_type = 'menuitem'
+                AND te.lang = le.lang
+            WHERE li.user_id = p_user_id AND li.menu_id <> p_menu_id
+            AND (te.embedding <=> le.embedding) <= 0.3
+            ORDER BY te.embedding <=> le.embedding
+            LIMIT 1
+        )
+    ),
+    ARRAY(
+        SELECT c.ingredient_name
+        FROM compat c, prefs p
+        WHERE lower(c.ingredient_name) IN (SELECT lower(di) FROM unnest(p.disliked_ingredients) di)
+        ORDER BY c.ingredient_name
+    ),
+    ARRAY(SELECT dc.diet FROM diet_compat dc ORDER BY dc.diet),
+    ARRAY(SELECT dc.score FROM diet_compat dc ORDER BY dc.diet)
+$$;
+
+-- explain_menuitem for a page of cards at once
+CREATE OR REPLACE FUNCTION kueater.explain_menuitems(
+    p_user_id UUID,
+    p_menu_ids UUID[]
+)
+RETURNS TABLE (
+    menu_id UUID,
+    cuisine TEXT,
+    similar_liked_dish TEXT,
+    disliked_ingredients TEXT[],
+    diets TEXT[],
+    diet_scores DOUBLE PRECISION[]
+)
+LANGUAGE sql STABLE
+AS $$
+    SELECT ids.id, e.*
+    FROM unnest(p_menu_ids) AS ids(id)
+    CROSS JOIN LATERAL kueater.explain_menuitem(p_user_id, ids.id) e
+$$;
@@ -0,0 +1,49 @@
+-- Allergen safety on every menu surface: each user either sees a warning on items
+-- containing their allergens, or never sees those items at all.
+
+DO $$ BEGIN
+    CREATE TYPE kueater.allergen_policy AS ENUM ('warn', 'hide');
+EXCEPTION
+    WHEN duplicate_object THEN null;
+END; $$;
+
+ALTER TABLE kueater.userprofile
+ADD COLUMN IF NOT EXISTS allergen_policy kueater.allergen_policy NOT NULL DEFAULT 'warn';
+
+-- The user's allergens found in each given item, with the highest score among its ingredients.
+-- Only items with at least one exposure are returned. Same rule as kueater.user_allergen_conflict.
+CREATE OR REPLACE FUNCTION kueater.allergen_exposure(
+    p_user_id UUID,
+    p_menu_ids UUID[]
+)
+RETURNS TABLE (
+    menu_id UUID,
+    allergens TEXT[],
+    scores DOUBLE PRECISION[],      -- Same order as allergens
+    hide BOOLEAN                    -- The user's policy is to hide these items
+)
+LANGUAGE sql STABLE
+AS $$
+    WITH user_allergies AS (
+        SELECT up.allergies
+        FROM kueater.user_profile_preferences upp
+        JOIN kueater.user_preferences up ON up.id = upp.preferences_id
+        WHERE upp.user_id = p_user_id
+    ),
+    exposure AS (
+        SELECT mi.menu_id, ias.allergen::TEXT AS allergen, MAX(ias.score)::DOUBLE PRECISION AS score
+        FROM unnest(p_menu_ids) AS ids(id)
+        JOIN kueater.menu_ingredient mi ON mi.menu_id = ids.id
+        JOIN kueater.ingredient_allergen_score ias ON ias.ingredient_id = mi.ingredient_id
+        JOIN user_allergies ua ON ias.allergen = ANY (ua.allergies)
+        WHERE ias.score > 0
+        GROUP BY mi.menu_id, ias.allergen
+    )
+    SELECT
+    e.menu_id,
+    array_agg(e.allergen ORDER BY e.allergen),
+    array_agg(e.score ORDER BY e.allergen),
+    COALESCE((SELECT u.allergen_policy = 'hide' FROM kueater.userprofile u WHERE u.id = p_user_id), FALSE)
+    FROM exposure e
+    GROUP BY e.menu_id
+$$;
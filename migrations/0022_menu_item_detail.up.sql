@@ -0,0 +1,71 @@
+-- Everything the item detail page shows beyond its card, evaluated for one user
+
+-- Diets and allergens are the user's own, scored from kueater.get_menuitem_compatibility_score:
+-- diets get the item's worst ingredient score, as in kueater.explain_menuitem, allergens the
+-- item's highest, 0 when no ingredient carries it.
+-- Items without ingredient data get no diet or allergen entries, their compatibility is unknown.
+CREATE OR REPLACE FUNCTION kueater.menuitem_detail(
+    p_user_id UUID,
+    p_menu_id UUID
+)
+RETURNS TABLE (
+    cuisine TEXT,
+    food_type TEXT,
+    ingredient_ids UUID[],
+    ingredient_names TEXT[],        -- Same order as ingredient_ids
+    diets TEXT[],
+    diet_scores DOUBLE PRECISION[], -- Same order as diets
+    allergens TEXT[],
+    allergen_scores DOUBLE PRECISION[], -- Same order as allergens
+    likes INT4,
+    dislikes INT4,
+    saves INT4
+)
+LANGUAGE sql STABLE
+AS $$
+    WITH prefs AS (
+        SELECT up.diets, up.allergies
+        FROM kueater.user_profile_preferences upp
+        JOIN kueater.user_preferences up ON up.id = upp.preferences_id
+        WHERE upp.user_id = p_user_id
+    ),
+    ingredients AS (
+        SELECT i.id, i.name
+        FROM kueater.menu_ingredient mi
+        JOIN kueater.ingredient i ON i.id = mi.ingredient_id
+        WHERE mi.menu_id = p_menu_id
+    ),
+    compat AS (
+        SELECT * FROM kueater.get_menuitem_compatibility_score(p_menu_id)
+    ),
+    diet_compat AS (
+        SELECT d.diet::TEXT AS diet,
+        MIN(COALESCE((c.diet_scores ->> d.diet::TEXT)::DOUBLE PRECISION, 0)) AS score
+        FROM prefs p
+        CROSS JOIN unnest(p.diets) AS d(diet)
+        CROSS JOIN compat c
+        GROUP BY d.diet
+    ),
+    allergen_risk AS (
+        SELECT a.allergen::TEXT AS allergen,
+        MAX(COALESCE((c.allergen_scores ->> a.allergen::TEXT)::DOUBLE PRECISION, 0)) AS score
+        FROM prefs p
+        CROSS JOIN unnest(p.allergies) AS a(allergen)
+        CROSS JOIN compat c
+        GROUP BY a.allergen
+    )
+    SELECT
+    m.cuisine,
+    m.food_type,
+    ARRAY(SELECT i.id FROM ingredients i ORDER BY i.name),
+    ARRAY(SELECT i.name FROM ingredients i ORDER BY i.name),
+    ARRAY(SELECT dc.diet FROM diet_compat dc ORDER BY dc.diet),
+    ARRAY(SELECT dc.score FROM diet_compat dc ORDER BY dc.diet),
+    ARRAY(SELECT ar.allergen FROM allergen_risk ar ORDER BY ar.allergen),
+    ARRAY(SELECT ar.score FROM allergen_risk ar ORDER BY ar.allergen),
+    (SELECT COUNT(*) FROM kueater.liked_item li WHERE li.menu_id = m.id)::INT4,
+    (SELECT COUNT(*) FROM kueater.disliked_item di WHERE di.menu_id = m.id)::INT4,
+    (SELECT COUNT(*) FROM kueater.saved_item si WHERE si.menu_id = m.id)::INT4
+    FROM kueater.menuitem m
+    WHERE m.id = p_menu_id
+$$;
@@ -0,0 +1,62 @@
+-- Per item signals for the in-process fallback recommender, used until the agent has scored the user.
+-- The score itself is computed by the backend, see src/service/home/fallback.rs.
+
+CREATE OR REPLACE FUNCTION kueater.fallback_menuitem_features(
+    p_user_id UUID
+)
+RETURNS TABLE (
+    menu_id UUID,
+    cuisine_match BOOLEAN,              -- Cuisine is one the user picked
+    matched_dish TEXT,                  -- Favourite dish closest to the item's name
+    dish_similarity DOUBLE PRECISION,   -- word_similarity of matched_dish, 0 without one
+    disliked_ingredients INT4,          -- Ingredients the user listed as disliked
+    diet_score DOUBLE PRECISION,        -- Worst ingredient score over the user's diets, NULL when unknown
+    diet_conflict BOOLEAN,
+    allergen_conflict BOOLEAN,
+    likes INT4,
+    dislikes INT4,
+    saves INT4
+)
+LANGUAGE sql STABLE
+AS $$
+    WITH prefs AS (
+        SELECT up.diets, up.cuisines, up.disliked_ingredients, up.favorite_dishes
+        FROM kueater.user_profile_preferences upp
+        JOIN kueater.user_preferences up ON up.id = upp.preferences_id
+        WHERE upp.user_id = p_user_id
+    )
+    SELECT
+    m.id,
+    COALESCE(lower(m.cuisine) IN (SELECT lower(c) FROM prefs p, unnest(p.cuisines) c), FALSE),
+    fav.dish,
+    COALESCE(fav.similarity, 0)::DOUBLE PRECISION,
+    (
+        SELECT COUNT(*)
+        FROM kueater.menu_ingredient mi
+        JOIN kueater.ingredient i ON i.id = mi.ingredient_id
+        CROSS JOIN prefs p
+        WHERE mi.menu_id = m.id
+        AND lower(i.name) IN (SELECT lower(di) FROM unnest(p.disliked_ingredients) di)
+    )::INT4,
+    (
+        SELECT MIN(COALESCE(ids.score, 0))::DOUBLE PRECISION
+        FROM prefs p
+        CROSS JOIN unnest(p.diets) AS d(diet)
+        JOIN kueater.menu_ingredient mi ON mi.menu_id = m.id
+        LEFT JOIN kueater.ingredient_diet_score ids
+            ON ids.ingredient_id = mi.ingredient_id AND ids.diet = d.diet
+    ),
+    COALESCE(r.diet_conflict, FALSE),
+    COALESCE(r.allergen_conflict, FALSE),
+    (SELECT COUNT(*) FROM kueater.liked_item li WHERE li.menu_id = m.id)::INT4,
+    (SELECT COUNT(*) FROM kueater.disliked_item di WHERE di.menu_id = m.id)::INT4,
+    (SELECT COUNT(*) FROM kueater.saved_item si WHERE si.menu_id = m.id)::INT4
+    FROM kueater.menuitem m
+    LEFT JOIN LATERAL (
+        SELECT d AS dish, word_similarity(d, m.name) AS similarity
+        FROM prefs p, unnest(p.favorite_dishes) AS d
+        ORDER BY word_similarity(d, m.name) DESC, d
+        LIMIT 1
+    ) fav ON TRUE
+    LEFT JOIN LATERAL kueater.menuitem_score_reasons(p_user_id, m.id) r ON TRUE
+$$;
@@ -0,0 +1,69 @@
+-- Item-item collaborative filtering, refreshed in the background by the server.
+-- Two items are neighbours when the same users liked or saved both.
+
+CREATE TABLE IF NOT EXISTS kueater.item_neighbors (
+    menu_id UUID REFERENCES kueater.menuitem ON DELETE CASCADE,
+    neighbor_id UUID REFERENCES kueater.menuitem ON DELETE CASCADE,
+    similarity DOUBLE PRECISION NOT NULL,
+    co_users INTEGER NOT NULL,      -- Users who liked or saved both items
+    refreshed_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (menu_id, neighbor_id)
+);
+
+CREATE INDEX IF NOT EXISTS item_neighbors_rank_idx ON kueater.item_neighbors (menu_id, similarity DESC);
+
+-- Cosine similarity over the sets of users who liked or saved each item
+--      similarity(a, b) = co_users(a, b) / sqrt(users(a) * users(b))
+-- Only pairs shared by at least p_min_co_users users are kept, then the p_top_k closest per item.
+-- The table is replaced as a whole, so items nobody interacts with anymore lose their neighbours.
+CREATE OR REPLACE FUNCTION kueater.refresh_item_neighbors(
+    p_top_k INT DEFAULT 20,
+    p_min_co_users INT DEFAULT 2
+)
+RETURNS VOID
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    CREATE TEMPORARY TABLE fresh_item_neighbors ON COMMIT DROP AS
+    WITH interactions AS (
+        SELECT user_id, menu_id FROM kueater.liked_item
+        UNION
+        SELECT user_id, menu_id FROM kueater.saved_item
+    ),
+    item_users AS (
+        SELECT menu_id, COUNT(*) AS users
+        FROM interactions
+        GROUP BY menu_id
+    ),
+    pairs AS (
+        SELECT a.menu_id, b.menu_id AS neighbor_id, COUNT(*) AS co_users
+        FROM interactions a
+        JOIN interactions b ON a.user_id = b.user_id AND a.menu_id <> b.menu_id
+        GROUP BY a.menu_id, b.menu_id
+        HAVING COUNT(*) >= p_min_co_users
+    ),
+    scored AS (
+        SELECT
+            p.menu_id,
+            p.neighbor_id,
+            p.co_users / sqrt(ua.users * ub.users::DOUBLE PRECISION) AS similarity,
+            p.co_users,
+            ROW_NUMBER() OVER (
+                PARTITION BY p.menu_id
+                ORDER BY p.co_users / sqrt(ua.users * ub.users::DOUBLE PRECISION) DESC, p.neighbor_id
+            ) AS rn
+        FROM pairs p
+        JOIN item_users ua ON ua.menu_id = p.menu_id
+        JOIN item_users ub ON ub.menu_id = p.neighbor_id
+    )
+    SELECT menu_id, neighbor_id, similarity, co_users::INTEGER AS co_users
+    FROM scored
+    WHERE rn <= p_top_k;
+
+    DELETE FROM kueater.item_neighbors;
+
+    INSERT INTO kueater.item_neighbors (menu_id, neighbor_id, similarity, co_users, refreshed_at)
+    SELECT menu_id, neighbor_id, similarity, co_users, NOW()
+    FROM fresh_item_neighbors;
+END;
+$$;
@@ -0,0 +1,43 @@
+-- What the backend compares menu items on when spreading a page over stalls and cuisines,
+-- see src/service/home/diversity.rs
+
+CREATE OR REPLACE FUNCTION kueater.menuitem_diversity_features(
+    p_menu_ids UUID[]
+)
+RETURNS TABLE (
+    menu_id UUID,
+    stall_id UUID,
+    cuisine TEXT
+)
+LANGUAGE sql STABLE
+AS $$
+    SELECT
+    m.id,
+    (SELECT MIN(sm.stall_id::TEXT)::UUID FROM kueater.stall_menu sm WHERE sm.menu_id = m.id),
+    lower(m.cuisine)
+    FROM kueater.menuitem m
+    WHERE m.id = ANY (p_menu_ids)
+$$;
+
+-- Cosine similarity of each pair of the given items' stored embeddings, each pair once with
+-- item_a < item_b. Compared within the same embedding language, the closest language wins.
+-- Pairs without embeddings in a common language are left out.
+CREATE OR REPLACE FUNCTION kueater.menuitem_pairwise_similarity(
+    p_menu_ids UUID[]
+)
+RETURNS TABLE (
+    item_a UUID,
+    item_b UUID,
+    similarity DOUBLE PRECISION
+)
+LANGUAGE sql STABLE
+AS $$
+    SELECT a.object_id, b.object_id, MAX(1 - (a.embedding <=> b.embedding))::DOUBLE PRECISION
+    FROM kueater.embeddings a
+    JOIN kueater.embeddings b
+        ON b.object_type = a.object_type AND b.lang = a.lang AND a.object_id < b.object_id
+    WHERE a.object_type = 'menuitem'
+    AND a.object_id = ANY (p_menu_ids)
+    AND b.object_id = ANY (p_menu_ids)
+    GROUP BY a.object_id, b.object_id
+$$;
@@ -0,0 +1,115 @@
+-- Private negative feedback: items a user is not interested in and stalls they hid.
+-- Unlike dislikes these change no public count, they only leave personalized surfaces.
+
+CREATE TABLE IF NOT EXISTS kueater.hidden_item (
+    user_id UUID REFERENCES kueater.userprofile ON DELETE CASCADE,
+    menu_id UUID REFERENCES kueater.menuitem ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, menu_id)
+);
+
+CREATE TABLE IF NOT EXISTS kueater.hidden_stall (
+    user_id UUID REFERENCES kueater.userprofile ON DELETE CASCADE,
+    stall_id UUID REFERENCES kueater.stall ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, stall_id)
+);
+
+-- Whether the user hid the item itself or a stall selling it
+CREATE OR REPLACE FUNCTION kueater.user_hidden_menuitem(
+    p_user_id UUID,
+    p_menu_id UUID
+)
+RETURNS BOOLEAN
+LANGUAGE sql STABLE
+AS $$
+    SELECT EXISTS (
+        SELECT 1 FROM kueater.hidden_item hi
+        WHERE hi.user_id = p_user_id AND hi.menu_id = p_menu_id
+    )
+    OR EXISTS (
+        SELECT 1
+        FROM kueater.hidden_stall hs
+        JOIN kueater.stall_menu sm ON sm.stall_id = hs.stall_id
+        WHERE hs.user_id = p_user_id AND sm.menu_id = p_menu_id
+    )
+$$;
+
+CREATE OR REPLACE FUNCTION kueater.toggle_hide_menu(
+    p_user_id UUID,
+    p_menu_id UUID,
+    p_hidden BOOLEAN
+)
+RETURNS VOID
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    IF p_hidden THEN
+        INSERT INTO kueater.hidden_item (user_id, menu_id)
+        VALUES (p_user_id, p_menu_id)
+        ON CONFLICT DO NOTHING;
+    ELSE
+        DELETE FROM kueater.hidden_item
+        WHERE user_id = p_user_id AND menu_id = p_menu_id;
+    END IF;
+END;
+$$;
+
+CREATE OR REPLACE FUNCTION kueater.toggle_hide_stall(
+    p_user_id UUID,
+    p_stall_id UUID,
+    p_hidden BOOLEAN
+)
+RETURNS VOID
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    IF p_hidden THEN
+        INSERT INTO kueater.hidden_stall (user_id, stall_id)
+        VALUES (p_user_id, p_stall_id)
+        ON CONFLICT DO NOTHING;
+    ELSE
+        DELETE FROM kueater.hidden_stall
+        WHERE user_id = p_user_id AND stall_id = p_stall_id;
+    END IF;
+END;
+$$;
+
+-- Same as in migration 0018, hidden items are always left out
+CREATE OR REPLACE FUNCTION kueater.similar_menuitems(
+    p_menu_id UUID,
+    p_user_id UUID,
+    p_limit INT DEFAULT 10,
+    p_exclude_disliked BOOLEAN DEFAULT TRUE,
+    p_exclude_allergens BOOLEAN DEFAULT TRUE
+)
+RETURNS TABLE (
+    menu_id UUID,
+    distance DOUBLE PRECISION
+)
+LANGUAGE sql STABLE
+AS $$
+    SELECT n.object_id, MIN(n.distance)
+    FROM kueater.embeddings src
+    CROSS JOIN LATERAL (
+        -- Oversampled so exclusions below still leave enough items
+        SELECT e.object_id, (e.embedding <=> src.embedding)::DOUBLE PRECISION AS distance
+        FROM kueater.embeddings e
+        WHERE e.object_type = 'menuitem'
+        AND e.lang = src.lang
+        AND e.object_id <> src.object_id
+        ORDER BY e.embedding <=> src.embedding
+        LIMIT p_limit * 4
+    ) n
+    WHERE src.object_type = 'menuitem'
+    AND src.object_id = p_menu_id
+    AND NOT (p_exclude_disliked AND EXISTS (
+        SELECT 1 FROM kueater.disliked_item di
+        WHERE di.user_id = p_user_id AND di.menu_id = n.object_id
+    ))
+    AND NOT (p_exclude_allergens AND kueater.user_allergen_conflict(p_user_id, n.object_id))
+    AND NOT kueater.user_hidden_menuitem(p_user_id, n.object_id)
+    GROUP BY n.object_id
+    ORDER BY 2, 1
+    LIMIT p_limit
+$$;
@@ -0,0 +1,136 @@
+-- Scoring generations: every scoring run of a user gets its own id, written on its
+-- menuitem_scores rows. Recommendation pages carry the generation they started with,
+-- so later pages keep reading that run even after the agent staled it and the view
+-- was refreshed. Generations start at 1, 0 is left to the scores of before this migration.
+
+CREATE TABLE IF NOT EXISTS kueater.menuitem_score_generation (
+    user_id UUID PRIMARY KEY REFERENCES kueater.userprofile ON DELETE CASCADE,
+    generation BIGINT NOT NULL DEFAULT 1
+);
+
+ALTER TABLE kueater.menuitem_scores
+ADD COLUMN IF NOT EXISTS generation BIGINT;
+
+-- Existing scores: stale ones are an older run than the current ones
+INSERT INTO kueater.menuitem_score_generation (user_id, generation)
+SELECT DISTINCT user_id, 1
+FROM kueater.menuitem_scores
+WHERE stale = FALSE AND user_id IS NOT NULL
+ON CONFLICT (user_id) DO NOTHING;
+
+UPDATE kueater.menuitem_scores
+SET generation = CASE WHEN stale THEN 0 ELSE 1 END
+WHERE generation IS NULL;
+
+ALTER TABLE kueater.menuitem_scores
+ALTER COLUMN generation SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS menuitem_scores_generation_idx
+ON kueater.menuitem_scores (user_id, generation, score DESC, id);
+
+-- Generation a user's next scores start when they have no counter yet: after any they have
+CREATE OR REPLACE FUNCTION kueater.first_menuitem_score_generation(p_user_id UUID)
+RETURNS BIGINT
+LANGUAGE sql STABLE
+AS $$
+    SELECT COALESCE(MAX(generation), 0) + 1
+    FROM kueater.menuitem_scores
+    WHERE user_id = p_user_id
+$$;
+
+-- Staling the scores of a user starts their next generation
+CREATE OR REPLACE FUNCTION kueater.stale_menuitem_scores_of(p_user_id UUID)
+RETURNS VOID
+LANGUAGE plpgsql
+AS
+$$
+BEGIN
+    UPDATE kueater.menuitem_scores
+    SET stale = TRUE
+    WHERE user_id = p_user_id AND stale = FALSE;
+
+    INSERT INTO kueater.menuitem_score_generation (user_id, generation)
+    VALUES (p_user_id, kueater.first_menuitem_score_generation(p_user_id))
+    ON CONFLICT (user_id) DO UPDATE
+    SET generation = kueater.menuitem_score_generation.generation + 1;
+END;
+$$;
+
+-- Stale scores of a generation are dropped once a newer generation of the user has had rows
+-- for p_retention_secs. Listings of the old generation started before the newer one was
+-- written, so with the retention at least the cursor TTL none of them can still be paging.
+-- Returns the number of rows removed.
+CREATE OR REPLACE FUNCTION kueater.prune_menuitem_score_generations(p_retention_secs INT DEFAULT 86400)
+RETURNS BIGINT
+LANGUAGE sql
+AS $$
+    WITH generation_start AS (
+        SELECT user_id, generation, MIN(created_at) AS started_at
+        FROM kueater.menuitem_scores
+        GROUP BY user_id, generation
+    ),
+    superseded AS (
+        SELECT g.user_id, g.generation
+        FROM generation_start g
+        WHERE EXISTS (
+            SELECT 1 FROM generation_start n
+            WHERE n.user_id = g.user_id AND n.generation > g.generation
+            AND n.started_at < LOCALTIMESTAMP - make_interval(secs => p_retention_secs)
+        )
+    ),
+    pruned AS (
+        DELETE FROM kueater.menuitem_scores ms
+        USING superseded s
+        WHERE ms.user_id = s.user_id AND ms.generation = s.generation AND ms.stale
+        RETURNING 1
+    )
+    SELECT COUNT(*) FROM pruned
+$$;
+
+-- The agent may write the generation itself, otherwise new scores join the user's current one
+CREATE OR REPLACE FUNCTION kueater.fill_menuitem_score_generation()
+RETURNS TRIGGER
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    IF NEW.generation IS NULL AND NEW.user_id IS NOT NULL THEN
+        INSERT INTO kueater.menuitem_score_generation (user_id, generation)
+        VALUES (NEW.user_id, kueater.first_menuitem_score_generation(NEW.user_id))
+        ON CONFLICT (user_id) DO NOTHING;
+
+        SELECT g.generation INTO NEW.generation
+        FROM kueater.menuitem_score_generation g
+        WHERE g.user_id = NEW.user_id;
+    END IF;
+    -- Scores of no user are never paged
+    NEW.generation := COALESCE(NEW.generation, 0);
+    RETURN NEW;
+END;
+$$;
+
+CREATE OR REPLACE TRIGGER menuitem_scores_generation
+BEFORE INSERT ON kueater.menuitem_scores
+FOR EACH ROW EXECUTE FUNCTION kueater.fill_menuitem_score_generation();
+
+-- Recreate the view with the generation
+DROP MATERIALIZED VIEW IF EXISTS kueater.current_menuitem_scores;
+
+CREATE MATERIALIZED VIEW kueater.current_menuitem_scores AS
+SELECT
+    id,
+    user_id,
+    menu_id,
+    score,
+    reasoning,
+    matched_dish,
+    matched_ingredient,
+    COALESCE(diet_conflict, FALSE) AS diet_conflict,
+    COALESCE(allergen_conflict, FALSE) AS allergen_conflict,
+    generation,
+    created_at
+FROM kueater.menuitem_scores
+WHERE stale = FALSE
+ORDER BY score DESC;
+
+CREATE UNIQUE INDEX unique_current_menuitem_scores ON kueater.current_menuitem_scores (id);
+CREATE INDEX IF NOT EXISTS current_menuitem_scores_user_idx ON kueater.current_menuitem_scores (user_id, score DESC);
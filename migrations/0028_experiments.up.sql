@@ -0,0 +1,190 @@
+-- A/B experiments on the home rails and ranking. Users are bucketed by a hash of their id,
+-- salted with the experiment id, so each user stays in one variant of an experiment and
+-- the variants of different experiments are independent.
+
+CREATE TABLE IF NOT EXISTS kueater.experiment (
+    id TEXT PRIMARY KEY,
+    surface TEXT NOT NULL,      -- Handler reading the parameters: for_you, recommendations, top_stall or search
+    description TEXT NOT NULL DEFAULT '',
+    enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS kueater.experiment_variant (
+    experiment_id TEXT REFERENCES kueater.experiment ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    weight INTEGER NOT NULL DEFAULT 1 CHECK (weight >= 0),     -- Share of users, relative to the other variants
+    PRIMARY KEY (experiment_id, name)
+);
+
+-- Parameters a variant overrides, anything missing keeps the server's default.
+-- A variant without parameters is the control.
+CREATE TABLE IF NOT EXISTS kueater.experiment_param (
+    experiment_id TEXT,
+    variant TEXT,
+    key TEXT NOT NULL,
+    value DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (experiment_id, variant, key),
+    FOREIGN KEY (experiment_id, variant) REFERENCES kueater.experiment_variant ON DELETE CASCADE
+);
+
+-- One row per user and experiment, from the first time the variant was served to them
+CREATE TABLE IF NOT EXISTS kueater.experiment_exposure (
+    experiment_id TEXT REFERENCES kueater.experiment ON DELETE CASCADE,
+    user_id UUID REFERENCES kueater.userprofile ON DELETE CASCADE,
+    variant TEXT NOT NULL,
+    first_exposed_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    last_exposed_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    exposures INTEGER NOT NULL DEFAULT 1,
+    PRIMARY KEY (experiment_id, user_id)
+);
+
+-- Variant weights changed while running can move a user, their exposure then starts over
+CREATE OR REPLACE FUNCTION kueater.log_experiment_exposure(
+    p_user_id UUID,
+    p_experiment_ids TEXT[],
+    p_variants TEXT[]
+)
+RETURNS VOID
+LANGUAGE sql
+AS $$
+    INSERT INTO kueater.experiment_exposure (experiment_id, user_id, variant)
+    SELECT e.experiment_id, p_user_id, e.variant
+    FROM UNNEST(p_experiment_ids, p_variants) AS e(experiment_id, variant)
+    ON CONFLICT (experiment_id, user_id) DO UPDATE
+    SET first_exposed_at = CASE
+            WHEN kueater.experiment_exposure.variant = EXCLUDED.variant
+            THEN kueater.experiment_exposure.first_exposed_at
+            ELSE NOW()
+        END,
+        exposures = CASE
+            WHEN kueater.experiment_exposure.variant = EXCLUDED.variant
+            THEN kueater.experiment_exposure.exposures + 1
+            ELSE 1
+        END,
+        variant = EXCLUDED.variant,
+        last_exposed_at = NOW();
+$$;
+
+-- Like and save rates of each variant: the share of exposed users who liked or saved
+-- any item after their first exposure, and the likes and saves per exposed user.
+CREATE OR REPLACE FUNCTION kueater.experiment_results(p_experiment_id TEXT)
+RETURNS TABLE (
+    variant TEXT,
+    users BIGINT,
+    liking_users BIGINT,
+    saving_users BIGINT,
+    like_rate DOUBLE PRECISION,
+    save_rate DOUBLE PRECISION,
+    likes_per_user DOUBLE PRECISION,
+    saves_per_user DOUBLE PRECISION
+)
+LANGUAGE sql STABLE
+AS $$
+    WITH activity AS (
+        SELECT
+            e.variant,
+            (
+                SELECT COUNT(*) FROM kueater.liked_item li
+                WHERE li.user_id = e.user_id AND li.created_at >= e.first_exposed_at
+            ) AS likes,
+            (
+                SELECT COUNT(*) FROM kueater.saved_item si
+                WHERE si.user_id = e.user_id AND si.created_at >= e.first_exposed_at
+            ) AS saves
+        FROM kueater.experiment_exposure e
+        WHERE e.experiment_id = p_experiment_id
+    )
+    SELECT
+        a.variant,
+        COUNT(*),
+        COUNT(*) FILTER (WHERE a.likes > 0),
+        COUNT(*) FILTER (WHERE a.saves > 0),
+        (COUNT(*) FILTER (WHERE a.likes > 0))::DOUBLE PRECISION / COUNT(*),
+        (COUNT(*) FILTER (WHERE a.saves > 0))::DOUBLE PRECISION / COUNT(*),
+        AVG(a.likes)::DOUBLE PRECISION,
+        AVG(a.saves)::DOUBLE PRECISION
+    FROM activity a
+    GROUP BY a.variant
+    ORDER BY a.variant
+$$;
+
+-- Stall scores of migration 0011 for any weights, so variants can rank stalls
+-- with their own weights without touching kueater.stall_rank
+CREATE OR REPLACE FUNCTION kueater.stall_rank_scores(
+    p_like_weight DOUBLE PRECISION DEFAULT 0.4,
+    p_review_weight DOUBLE PRECISION DEFAULT 0.3,
+    p_rating_weight DOUBLE PRECISION DEFAULT 0.3,
+    p_prior_weight DOUBLE PRECISION DEFAULT 5
+)
+RETURNS TABLE (
+    stall_id UUID,
+    score DOUBLE PRECISION,
+    bayes_rating DOUBLE PRECISION
+)
+LANGUAGE sql STABLE
+AS $$
+    WITH global AS (
+        SELECT COALESCE(AVG(score), 3)::DOUBLE PRECISION AS avg
+        FROM kueater.review
+    ),
+    stall_reviews AS (
+        SELECT
+            stall,
+            COUNT(*) AS review_count,
+            SUM(score) AS score_sum
+        FROM kueater.review
+        GROUP BY stall
+    ),
+    stall_likes AS (
+        SELECT
+            stall_id,
+            COUNT(*) AS like_count
+        FROM kueater.liked_stall
+        GROUP BY stall_id
+    ),
+    scored AS (
+        SELECT
+            s.id,
+            (p_prior_weight * g.avg + COALESCE(sr.score_sum, 0))
+                / (p_prior_weight + COALESCE(sr.review_count, 0)) AS bayes_rating,
+            COALESCE(sl.like_count, 0) AS like_count,
+            COALESCE(sr.review_count, 0) AS review_count
+        FROM kueater.stall s
+        CROSS JOIN global g
+        LEFT JOIN stall_reviews sr ON s.id = sr.stall
+        LEFT JOIN stall_likes sl ON s.id = sl.stall_id
+    )
+    SELECT
+        id,
+        (p_like_weight * like_count + p_review_weight * review_count + p_rating_weight * bayes_rating)::DOUBLE PRECISION,
+        bayes_rating::DOUBLE PRECISION
+    FROM scored
+$$;
+
+-- Same as in migration 0011, on top of kueater.stall_rank_scores
+CREATE OR REPLACE FUNCTION kueater.refresh_stall_rank(
+    p_like_weight DOUBLE PRECISION DEFAULT 0.4,
+    p_review_weight DOUBLE PRECISION DEFAULT 0.3,
+    p_rating_weight DOUBLE PRECISION DEFAULT 0.3,
+    p_prior_weight DOUBLE PRECISION DEFAULT 5
+)
+RETURNS VOID
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    INSERT INTO kueater.stall_rank (stall_id, rank, score, bayes_rating, refreshed_at)
+    SELECT
+        r.stall_id,
+        ROW_NUMBER() OVER (ORDER BY r.score DESC, r.stall_id)::INTEGER,
+        r.score,
+        r.bayes_rating,
+        NOW()
+    FROM kueater.stall_rank_scores(p_like_weight, p_review_weight, p_rating_weight, p_prior_weight) r
+    ON CONFLICT (stall_id) DO UPDATE
+    SET rank = EXCLUDED.rank,
+        score = EXCLUDED.score,
+        bayes_rating = EXCLUDED.bayes_rating,
+        refreshed_at = EXCLUDED.refreshed_at;
+END;
+$$;
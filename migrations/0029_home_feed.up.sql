@@ -0,0 +1,40 @@
+-- Anchor of the "Because You Like X" rail of the home feed: a favourite dish or ingredient
+-- of the user that enough of their current recommendations matched, as read by the
+-- InferLike rail. Drawn at random so the rail changes between visits, each candidate
+-- weighing one plus the items matching it that the user liked or saved.
+CREATE OR REPLACE FUNCTION kueater.home_feed_anchor(
+    p_user_id UUID,
+    p_min_items INT DEFAULT 3
+)
+RETURNS TEXT
+LANGUAGE sql VOLATILE
+AS $$
+    WITH candidates AS (
+        SELECT lower(w.word) AS word, MIN(w.word) AS label
+        FROM kueater.current_menuitem_scores cms
+        CROSS JOIN LATERAL (VALUES (cms.matched_dish), (cms.matched_ingredient)) AS w(word)
+        WHERE cms.user_id = p_user_id AND w.word IS NOT NULL
+        AND NOT cms.diet_conflict AND NOT cms.allergen_conflict
+        AND NOT kueater.user_hidden_menuitem(p_user_id, cms.menu_id)
+        GROUP BY lower(w.word)
+        HAVING COUNT(DISTINCT cms.menu_id) >= p_min_items
+    ),
+    engaged AS (
+        SELECT lower(w.word) AS word, COUNT(DISTINCT e.menu_id) AS items
+        FROM (
+            SELECT menu_id FROM kueater.liked_item WHERE user_id = p_user_id
+            UNION
+            SELECT menu_id FROM kueater.saved_item WHERE user_id = p_user_id
+        ) e
+        CROSS JOIN LATERAL kueater.menuitem_score_reasons(p_user_id, e.menu_id) r
+        CROSS JOIN LATERAL (VALUES (r.matched_dish), (r.matched_ingredient)) AS w(word)
+        WHERE w.word IS NOT NULL
+        GROUP BY lower(w.word)
+    )
+    SELECT c.label
+    FROM candidates c
+    LEFT JOIN engaged e ON e.word = c.word
+    -- Weighted sampling without replacement: the smallest exponential draw over weight wins
+    ORDER BY -ln(1 - random()) / (1 + COALESCE(e.items, 0))
+    LIMIT 1
+$$;
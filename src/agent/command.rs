@@ -7,6 +7,13 @@ pub enum Command {
     },
     Recommend {
         user_id: String
+    },
+    // Private negative feedback, object_type is "menuitem" or "stall"
+    Feedback {
+        user_id: String,
+        object_type: String,
+        object_id: String,
+        hidden: bool
     }
 }
 
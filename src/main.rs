@@ -6,6 +6,7 @@ use tonic::{transport::Server, Request, Response, Status};
 use tonic_web::GrpcWebLayer;
 use tonic_middleware::InterceptorFor;
 use std::env::var;
+use std::sync::Arc;
 use dotenv::dotenv;
 
 use middleware::{google_auth::{GoogleAuthClientInfo}, kueater_auth::{self, auth_service_server::{AuthService, AuthServiceServer}}};
@@ -87,6 +88,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         redirect_uri: var("GOOGLE_REDIRECT_URI").expect("GOOGLE_REDIRECT_URI not set")
     };
 
+    let _ranker = tokio::spawn(service::ranking::run_stall_rank_refresher(
+        pg.clone(), service::ranking::StallRankConfig::from_env()
+    ));
+
+    let _neighbors = tokio::spawn(service::neighbors::run_item_neighbors_refresher(
+        pg.clone(), service::neighbors::ItemNeighborsConfig::from_env()
+    ));
+
+    let _score_pruner = tokio::spawn(service::score_generations::run_score_generation_pruner(
+        pg.clone(), service::score_generations::ScorePruneConfig::from_env()
+    ));
+
+    let suggest_index = Arc::new(service::search::suggest::SuggestIndex::default());
+    let _suggest = tokio::spawn(service::search::suggest::run_suggest_index_refresher(
+        pg.clone(), suggest_index.clone()
+    ));
+
+    let experiments = Arc::new(service::experiments::Experiments::default());
+    let _experiments = tokio::spawn(service::experiments::run_experiments_refresher(
+        pg.clone(), experiments.clone()
+    ));
+
+    let embedding_cache = Arc::new(service::search::embedding_cache::EmbeddingCache::from_env());
+    let _cache_reporter = tokio::spawn(service::search::embedding_cache::run_embedding_cache_reporter(
+        embedding_cache.clone()
+    ));
+
     println!("Starting gRPC server...");
 
     let (tx, mut rx) = mpsc::channel::<AgentCommand>(1024);
@@ -95,7 +123,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let server_tx = tx.clone();
 
     let sv = tokio::spawn(async move {
-        let service = BackendService::new(pg_inner.clone(), server_tx);
+        let service = BackendService::new(pg_inner.clone(), server_tx, suggest_index, embedding_cache, experiments);
 
         let debug_svc = DebugService {
             pg_pool: pg_inner.clone()
@@ -141,6 +169,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         user_id: user_id.into()
                     });
                     client.new_recommendations(request).await.unwrap();
+                },
+                Command::Feedback {user_id, object_type, object_id, hidden} => {
+                    let request = Request::new(kueater_agent::FeedbackRequest {
+                        user_id, object_type, object_id, hidden
+                    });
+                    if let Err(e) = client.feedback(request).await {
+                        println!("Cannot send feedback to agent: {}", e);
+                    }
                 }
             }
         }
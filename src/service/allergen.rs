@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+use sqlx::types::Uuid;
+use sqlx::{Error, PgPool};
+
+use super::kueater::data::types::{AllergenExposure, AllergenWarning};
+
+#[derive(Debug, sqlx::FromRow)]
+struct ExposureRow {
+    menu_id: Uuid,
+    allergens: Vec<String>,
+    scores: Vec<f64>,
+    hide: bool
+}
+
+// The user's allergen exposure for a set of menu items, from kueater.allergen_exposure.
+// Unlike explanations this is not optional: if it cannot be loaded, the surface fails
+// rather than showing cards without their warnings.
+pub struct AllergenGuard {
+    hide: bool,
+    exposure: HashMap<String, AllergenWarning>
+}
+
+impl AllergenGuard {
+    pub async fn load(
+        pg_pool: &PgPool,
+        user_id: &Uuid,
+        menu_ids: &[Uuid]
+    ) -> Result<Self, Error> {
+        let rows: Vec<ExposureRow> = sqlx::query_as(
+            "SELECT * FROM kueater.allergen_exposure($1, $2)"
+        )
+        .bind(user_id)
+        .bind(menu_ids)
+        .fetch_all(pg_pool).await?;
+
+        Ok(Self::from_rows(rows))
+    }
+
+    // Items without a row have no exposure, including items without ingredient data
+    fn from_rows(rows: Vec<ExposureRow>) -> Self {
+        AllergenGuard {
+            hide: rows.iter().any(|r| r.hide),
+            exposure: rows.into_iter().map(|r| (
+                r.menu_id.to_string(),
+                AllergenWarning {
+                    allergens: r.allergens.into_iter().zip(r.scores).map(|(allergen, score)| {
+                        AllergenExposure { allergen, score }
+                    }).collect()
+                }
+            )).collect()
+        }
+    }
+
+    // False when the user hides items containing their allergens and this is one.
+    // Lists filter with this; lookups of a single item always return it, with its warning.
+    pub fn allows(&self, menu_id: &Uuid) -> bool {
+        !(self.hide && self.exposure.contains_key(&menu_id.to_string()))
+    }
+
+    // Warning for a card, keyed by menu uuid as in MenuCardProps
+    pub fn warning(&self, menu_id: &str) -> Option<AllergenWarning> {
+        self.exposure.get(menu_id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTAINS: Uuid = Uuid::from_u128(1);
+    const TRACE: Uuid = Uuid::from_u128(2);
+    const NONE: Uuid = Uuid::from_u128(3);
+    const NO_INGREDIENTS: Uuid = Uuid::from_u128(4);
+
+    // What kueater.allergen_exposure returns for the four items: a row for each item exposing
+    // one of the user's allergens, fully or as a trace, and none for the other two
+    fn guard(hide: bool) -> AllergenGuard {
+        AllergenGuard::from_rows(vec![
+            ExposureRow {
+                menu_id: CONTAINS,
+                allergens: vec!["Peanuts".to_string(), "Shellfish".to_string()],
+                scores: vec![1.0, 0.4],
+                hide
+            },
+            ExposureRow {
+                menu_id: TRACE,
+                allergens: vec!["Milk".to_string()],
+                scores: vec![0.1],
+                hide
+            }
+        ])
+    }
+
+    fn exposure(warning: Option<AllergenWarning>) -> Vec<(String, f64)> {
+        warning.unwrap().allergens.into_iter().map(|e| (e.allergen, e.score)).collect()
+    }
+
+    #[test]
+    fn warn_policy_keeps_every_item() {
+        let guard = guard(false);
+        for id in [CONTAINS, TRACE, NONE, NO_INGREDIENTS] {
+            assert!(guard.allows(&id));
+        }
+    }
+
+    #[test]
+    fn warn_policy_warns_on_contains_and_trace() {
+        let guard = guard(false);
+        assert_eq!(
+            exposure(guard.warning(&CONTAINS.to_string())),
+            vec![("Peanuts".to_string(), 1.0), ("Shellfish".to_string(), 0.4)]
+        );
+        assert_eq!(exposure(guard.warning(&TRACE.to_string())), vec![("Milk".to_string(), 0.1)]);
+        assert!(guard.warning(&NONE.to_string()).is_none());
+        assert!(guard.warning(&NO_INGREDIENTS.to_string()).is_none());
+    }
+
+    #[test]
+    fn hide_policy_drops_contains_and_trace() {
+        let guard = guard(true);
+        assert!(!guard.allows(&CONTAINS));
+        assert!(!guard.allows(&TRACE));
+        assert!(guard.allows(&NONE));
+        assert!(guard.allows(&NO_INGREDIENTS));
+    }
+
+    #[test]
+    fn hide_policy_still_warns_on_single_lookups() {
+        let guard = guard(true);
+        assert!(guard.warning(&CONTAINS.to_string()).is_some());
+        assert!(guard.warning(&TRACE.to_string()).is_some());
+        assert!(guard.warning(&NONE.to_string()).is_none());
+    }
+
+    #[test]
+    fn nothing_exposed_allows_everything() {
+        let guard = AllergenGuard::from_rows(vec![]);
+        for id in [CONTAINS, TRACE, NONE, NO_INGREDIENTS] {
+            assert!(guard.allows(&id));
+            assert!(guard.warning(&id.to_string()).is_none());
+        }
+    }
+}
@@ -1,8 +1,20 @@
+use std::sync::Arc;
+
 use sqlx::PgPool;
 use tokio::sync::mpsc;
 use tonic::{Request, Response, Status};
 use crate::AgentCommand;
 
+use super::experiments::Experiments;
+use super::home::cursor::CursorCodec;
+use super::home::diversity::DiversityConfig;
+use super::home::fallback::FallbackRanker;
+use super::ranking::StallRankConfig;
+use super::search::embedding_cache::EmbeddingCache;
+use super::search::fusion::FusionWeights;
+use super::search::session::SearchSessions;
+use super::search::suggest::SuggestIndex;
+
 use super::kueater::data::ku_eater_backend_server::KuEaterBackend;
 use super::kueater::data::*;
 use super::kueater::Empty;
@@ -12,14 +24,38 @@ pub type AgentCommandSender = mpsc::Sender<AgentCommand>;
 #[derive(Debug)]
 pub struct BackendService {
     pg_pool: PgPool,
-    sender: AgentCommandSender
+    sender: AgentCommandSender,
+    fusion_weights: FusionWeights,
+    fallback: FallbackRanker,
+    diversity_config: DiversityConfig,
+    cursors: CursorCodec,
+    stall_rank_config: StallRankConfig,
+    search_sessions: SearchSessions,
+    suggest_index: Arc<SuggestIndex>,
+    embedding_cache: Arc<EmbeddingCache>,
+    experiments: Arc<Experiments>
 }
 
 impl BackendService {
-    pub fn new(pg_pool: PgPool, sender: AgentCommandSender) -> Self {
+    pub fn new(
+        pg_pool: PgPool,
+        sender: AgentCommandSender,
+        suggest_index: Arc<SuggestIndex>,
+        embedding_cache: Arc<EmbeddingCache>,
+        experiments: Arc<Experiments>
+    ) -> Self {
         Self {
             pg_pool,
-            sender
+            sender,
+            fusion_weights: FusionWeights::from_env(),
+            fallback: FallbackRanker::from_env(),
+            diversity_config: DiversityConfig::from_env(),
+            cursors: CursorCodec::from_env(),
+            stall_rank_config: StallRankConfig::from_env(),
+            search_sessions: SearchSessions::from_env(),
+            suggest_index,
+            embedding_cache,
+            experiments
         }
     }
 }
@@ -61,6 +97,19 @@ impl KuEaterBackend for BackendService {
         super::getters::get_menu_item(&self.pg_pool, request).await
     }
 
+    async fn get_menu_item_detail(
+        &self, request: Recv<GetMenuItemDetailRequest>
+    ) -> Send<GetMenuItemDetailResponse> {
+        super::getters::get_menu_item_detail(&self.pg_pool, request).await
+    }
+
+    // Nearest items by stored embedding, for the menu detail page
+    async fn get_similar_items(
+        &self, request: Recv<GetSimilarItemsRequest>
+    ) -> Send<GetSimilarItemsResponse> {
+        super::similar::get_similar_items(&self.pg_pool, request).await
+    }
+
     async fn get_stall(
         &self, request: Recv<GetStallRequest>
     ) -> Send<types::StallDataTypeProps> {
@@ -73,49 +122,99 @@ impl KuEaterBackend for BackendService {
         super::getters::items_in_stall(&self.pg_pool, request).await
     }
 
-    // Get 40 menu items sorted by like count
+    // Get 40 menu items ranked by time-decayed likes, saves, dislikes and reviews
+    // within the requested window (today, this week or all time)
     async fn home_top_menu(
-        &self, request: Recv<Empty>
+        &self, request: Recv<home::TopMenuMsg>
     ) -> Send<home::TopMenuProps> {
-        super::home::top_menu(&self.pg_pool, request).await
+        super::home::top_menu(&self.pg_pool, &self.diversity_config, request).await
     }
 
-    // Get 10 stalls from like count and review count averaged
+    // Get 10 stalls from like count and review count averaged,
+    // users in a top_stall experiment get them ranked with their variant's weights
     async fn home_top_stall(
-        &self, request: Recv<Empty>
+        &self, request: Recv<home::TopStallMsg>
     ) -> Send<home::TopStallProps> {
-        super::home::top_stall(&self.pg_pool, request).await
+        super::home::top_stall(&self.pg_pool, &self.stall_rank_config, &self.experiments, request).await
     }
 
     // Randomly choose a favorite dish of user,
-    // then use it to find recommendations from reasoning with favorite dish.
+    // then use it to find recommendations whose score matched that favorite dish or ingredient.
     async fn home_infer_like(
         &self, request: Recv<home::InferLikeMsg>
     ) -> Send<home::InferLikeProps> {
-        super::home::infer_like(&self.pg_pool, request).await
+        super::home::infer_like(&self.pg_pool, &self.diversity_config, request).await
     }
 
-    // Select randomly 8 menu from user recommendations, fresh account = empty list
+    // Select 8 diverse menu from the top user recommendations, 2 of them from items liked by the same people,
+    // fresh account = top 8 of the fallback ranking
     async fn home_for_you(
         &self, request: Recv<home::ForYouMsg>
     ) -> Send<home::ForYouProps> {
-        super::home::for_you(&self.pg_pool, request).await
+        super::home::for_you(&self.pg_pool, &self.fallback, &self.diversity_config, &self.cursors, &self.experiments, request).await
+    }
+
+    // Items liked or saved by the same people as the given item
+    async fn home_also_liked(
+        &self, request: Recv<home::AlsoLikedMsg>
+    ) -> Send<home::AlsoLikedProps> {
+        super::home::also_liked(&self.pg_pool, request).await
     }
 
     // Getting list of recommendations from highest score -> lowest (score must be higher than 5)
-    // Fresh account -> gets menu by indexing in database
+    // Fresh account -> gets menu ranked by the in-process fallback scorer
     async fn home_get_recommendations(
         &self, request: Recv<home::GetRecommendationsMsg>
     ) -> Send<home::RecommendationsList> {
-        super::home::get_recommendations(&self.pg_pool, request).await
+        super::home::get_recommendations(&self.pg_pool, &self.fallback, &self.diversity_config, &self.cursors, &self.experiments, request).await
+    }
+
+    // The home page in one call: the server picks the rails, their order and the
+    // "Because You Like X" anchor, and fetches the rails concurrently
+    async fn get_home_feed(
+        &self, request: Recv<home::GetHomeFeedMsg>
+    ) -> Send<home::HomeFeed> {
+        super::home::feed::get_home_feed(
+            &self.pg_pool, &self.fallback, &self.diversity_config, &self.cursors,
+            &self.stall_rank_config, &self.experiments, request
+        ).await
     }
 
-    // The request fires once and retrieves all result,
-    // Sends a message to channel to agent client and calculate vectors.
+    // The first request ranks all results and caches them under a search session,
+    // Sends a message to channel to agent client and calculate vectors, unless the query's embedding is cached.
     // The vectors returned and we use PostgreSQL to get LIMIT 200 on menuitems which are closest to vectors.
-    // The stalls are calculated by ranking the presence in search results for menuitems.
+    // Text and vector results are merged with reciprocal rank fusion, each hit carries its relevance.
+    // Ingredient embeddings close to the query add the dishes containing them.
+    // The stalls are calculated by ranking the presence in search results for menuitems, fused with stall embedding matches.
+    // Each response holds one page, next_cursor fetches the next one from the cached ranking.
     async fn search(&self, request: Recv<search::SearchRequest>) -> Send<search::SearchResponse> {
-        super::search::search(&self.pg_pool, &self.sender, request).await
+        super::search::search(&self.pg_pool, &self.sender, &self.fusion_weights, &self.search_sessions, &self.embedding_cache, &self.experiments, request).await
+    }
+
+    // Typeahead from the in-memory prefix index, no agent round trip
+    async fn suggest_search(&self, request: Recv<search::SuggestRequest>) -> Send<search::SuggestResponse> {
+        super::search::suggest::suggest_search(&self.suggest_index, request).await
+    }
+
+    async fn recent_searches(&self, request: Recv<search::RecentSearchesRequest>) -> Send<search::RecentSearchesResponse> {
+        super::search::history::recent_searches(&self.pg_pool, request).await
+    }
+
+    async fn clear_search_history(&self, request: Recv<Empty>) -> Send<Empty> {
+        super::search::history::clear_search_history(&self.pg_pool, request).await
+    }
+
+    async fn set_search_history_enabled(&self, request: Recv<search::SetSearchHistoryEnabledRequest>) -> Send<Empty> {
+        super::search::history::set_search_history_enabled(&self.pg_pool, request).await
+    }
+
+    async fn trending_searches(&self, request: Recv<search::TrendingSearchesRequest>) -> Send<search::TrendingSearchesResponse> {
+        super::search::history::trending_searches(&self.pg_pool, request).await
+    }
+
+    // Only for users in kueater.admin_user
+    async fn zero_result_searches(&self, request: Recv<search::ZeroResultSearchesRequest>) -> Send<search::ZeroResultSearchesResponse> {
+        super::search::history::zero_result_searches(&self.pg_pool, request).await
     }
 
     async fn list_reviews(&self, request: Recv<review::ListReviewsRequest>) -> Send<review::ListReviewsResponse> {
@@ -156,6 +255,19 @@ impl KuEaterBackend for BackendService {
     }
     // End of tally functions
 
+    // Private "not interested" feedback, not tallied: it is sent to the agent directly
+    async fn hide_item(&self, request: Recv<activity::HideItemMsg>) -> Send<Empty> {
+        super::hidden::hide_item(&self.pg_pool, request, &self.sender).await
+    }
+
+    async fn hide_stall(&self, request: Recv<activity::HideStallMsg>) -> Send<Empty> {
+        super::hidden::hide_stall(&self.pg_pool, request, &self.sender).await
+    }
+
+    async fn hidden_items(&self, request: Recv<HiddenItemsRequest>) -> Send<HiddenItemsResponse> {
+        super::hidden::hidden_items(&self.pg_pool, request).await
+    }
+
     // Refer to create account for no-headache
     async fn save_profile(&self, request: Recv<SaveProfileRequest>) -> Send<Empty> {
         super::profile::save_profile(&self.pg_pool, request).await
@@ -164,4 +276,21 @@ impl KuEaterBackend for BackendService {
     async fn save_preferences(&self, request: Recv<SavePreferencesRequest>) -> Send<Empty> {
         super::profile::save_preferences(&self.pg_pool, request, &self.sender).await
     }
+
+    async fn set_allergen_policy(&self, request: Recv<SetAllergenPolicyRequest>) -> Send<Empty> {
+        super::profile::set_allergen_policy(&self.pg_pool, request).await
+    }
+
+    async fn list_canteens(&self, request: Recv<location::ListCanteensRequest>) -> Send<location::ListCanteensResponse> {
+        super::location::list_canteens(&self.pg_pool, request).await
+    }
+
+    async fn canteen_stalls(&self, request: Recv<location::CanteenStallsRequest>) -> Send<location::CanteenStallsResponse> {
+        super::location::canteen_stalls(&self.pg_pool, request).await
+    }
+
+    // Distance is from the stall's canteen, computed with haversine in the server
+    async fn nearby_stalls(&self, request: Recv<location::NearbyStallsRequest>) -> Send<location::NearbyStallsResponse> {
+        super::location::nearby_stalls(&self.pg_pool, request).await
+    }
 }
\ No newline at end of file
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+
+use super::env_period;
+
+// Surfaces reading variant parameters, kueater.experiment.surface
+pub const SURFACE_FOR_YOU: &str = "for_you";
+pub const SURFACE_RECOMMENDATIONS: &str = "recommendations";
+pub const SURFACE_TOP_STALL: &str = "top_stall";
+pub const SURFACE_SEARCH: &str = "search";
+
+#[derive(Debug, sqlx::FromRow)]
+struct ExperimentRow {
+    id: String,
+    surface: String,
+    variant: String,
+    weight: i32,
+    key: Option<String>,
+    value: Option<f64>
+}
+
+#[derive(Debug)]
+struct Variant {
+    name: String,
+    weight: u64,
+    params: HashMap<String, f64>
+}
+
+#[derive(Debug)]
+struct Experiment {
+    id: String,
+    surface: String,
+    variants: Vec<Variant>      // Sorted by name, so bucket boundaries do not depend on the query
+}
+
+impl Experiment {
+    fn variant_of(&self, user_id: &Uuid) -> Option<&Variant> {
+        let total: u64 = self.variants.iter().map(|v| v.weight).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut bucket = bucket(&self.id, user_id) % total;
+        for v in self.variants.iter() {
+            if bucket < v.weight {
+                return Some(v);
+            }
+            bucket -= v.weight;
+        }
+        None
+    }
+}
+
+// Parameters outside the range their consumer can work with: a zero prior weight divides
+// by zero in kueater.stall_rank_scores, a k of zero or below breaks reciprocal rank fusion
+fn param_error(key: &str, value: f64) -> Option<&'static str> {
+    if !value.is_finite() {
+        return Some("not a finite number");
+    }
+    match key {
+        "prior_weight" | "rrf_k" if value <= 0.0 => Some("not above zero"),
+        "also_liked" | "ingredient_max_distance" if value < 0.0 => Some("negative"),
+        _ => None
+    }
+}
+
+// Rows ordered by experiment and variant, as Experiments::reload selects them
+fn group(rows: Vec<ExperimentRow>) -> Vec<Experiment> {
+    let mut experiments: Vec<Experiment> = vec![];
+    for row in rows {
+        if experiments.last().map(|e| e.id != row.id).unwrap_or(true) {
+            experiments.push(Experiment { id: row.id, surface: row.surface, variants: vec![] });
+        }
+        let experiment = experiments.last_mut().unwrap();
+        if experiment.variants.last().map(|v| v.name != row.variant).unwrap_or(true) {
+            experiment.variants.push(Variant {
+                name: row.variant,
+                weight: row.weight.max(0) as u64,
+                params: HashMap::new()
+            });
+        }
+        if let (Some(key), Some(value)) = (row.key, row.value) {
+            experiment.variants.last_mut().unwrap().params.insert(key, value);
+        }
+    }
+    experiments
+}
+
+// A variant with a parameter out of range would fail or garble its surface for every user
+// in it, the whole experiment is left out until it is fixed
+fn usable(experiments: Vec<Experiment>) -> Vec<Experiment> {
+    experiments.into_iter().filter(|e| {
+        for v in e.variants.iter() {
+            for (key, value) in v.params.iter() {
+                if let Some(error) = param_error(key, *value) {
+                    println!("Skipping experiment {}: {} of variant {} is {}, {}", e.id, key, v.name, value, error);
+                    return false;
+                }
+            }
+        }
+        true
+    }).collect()
+}
+
+// FNV-1a over the experiment id and the user id: stable across restarts and servers,
+// unlike the standard library's hasher
+fn bucket(experiment_id: &str, user_id: &Uuid) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in experiment_id.as_bytes().iter().chain(b":").chain(user_id.as_bytes()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Variants a user was put in on one surface, with their merged parameters
+#[derive(Debug, Default)]
+pub struct Assignment {
+    variants: Vec<(String, String)>,    // (experiment, variant)
+    params: HashMap<String, f64>
+}
+
+impl Assignment {
+    pub fn param(&self, key: &str, default: f64) -> f64 {
+        self.params.get(key).copied().unwrap_or(default)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.variants.is_empty()
+    }
+}
+
+// Enabled experiments, reloaded from the database as a whole like the suggest index
+#[derive(Debug, Default)]
+pub struct Experiments {
+    running: RwLock<Arc<Vec<Experiment>>>
+}
+
+impl Experiments {
+    pub async fn reload(&self, pg_pool: &PgPool) -> Result<(), sqlx::Error> {
+        let rows: Vec<ExperimentRow> = sqlx::query_as(
+            "
+            SELECT e.id, e.surface, v.name AS variant, v.weight, p.key, p.value
+            FROM kueater.experiment e
+            JOIN kueater.experiment_variant v ON v.experiment_id = e.id
+            LEFT JOIN kueater.experiment_param p
+                ON p.experiment_id = v.experiment_id AND p.variant = v.name
+            WHERE e.enabled
+            ORDER BY e.id, v.name
+            "
+        ).fetch_all(pg_pool).await?;
+
+        *self.running.write().unwrap() = Arc::new(usable(group(rows)));
+        Ok(())
+    }
+
+    // Experiments on the same surface are expected to tune different parameters,
+    // on a clash the one with the greatest id wins
+    pub fn assign(&self, surface: &str, user_id: &Uuid) -> Assignment {
+        let running = self.running.read().unwrap().clone();
+
+        let mut assignment = Assignment::default();
+        for experiment in running.iter().filter(|e| e.surface == surface) {
+            if let Some(variant) = experiment.variant_of(user_id) {
+                assignment.variants.push((experiment.id.clone(), variant.name.clone()));
+                assignment.params.extend(variant.params.iter().map(|(k, v)| (k.clone(), *v)));
+            }
+        }
+        assignment
+    }
+}
+
+// Record that the user was served their variants, for kueater.experiment_results.
+// Failing to log never fails the request.
+pub async fn log_exposure(pg_pool: &PgPool, user_id: &Uuid, assignment: &Assignment) {
+    if assignment.is_empty() {
+        return;
+    }
+
+    let (experiment_ids, variants): (Vec<String>, Vec<String>) = assignment.variants.iter().cloned().unzip();
+
+    let result = sqlx::query("SELECT kueater.log_experiment_exposure($1, $2, $3)")
+        .bind(user_id)
+        .bind(&experiment_ids)
+        .bind(&variants)
+        .execute(pg_pool).await;
+
+    if let Err(e) = result {
+        println!("Cannot log experiment exposure: {}", e);
+    }
+}
+
+// Pick up experiments started, stopped or edited in the database
+pub async fn run_experiments_refresher(pg_pool: PgPool, experiments: Arc<Experiments>) {
+    let mut interval = tokio::time::interval(env_period("EXPERIMENTS_REFRESH_SECS", 60));
+    loop {
+        interval.tick().await;
+        if let Err(e) = experiments.reload(&pg_pool).await {
+            println!("Cannot reload experiments: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(id: &str, surface: &str, variant: &str, weight: i32, param: Option<(&str, f64)>) -> ExperimentRow {
+        ExperimentRow {
+            id: id.to_string(),
+            surface: surface.to_string(),
+            variant: variant.to_string(),
+            weight,
+            key: param.map(|(k, _)| k.to_string()),
+            value: param.map(|(_, v)| v)
+        }
+    }
+
+    fn experiments(rows: Vec<ExperimentRow>) -> Experiments {
+        let experiments = Experiments::default();
+        *experiments.running.write().unwrap() = Arc::new(usable(group(rows)));
+        experiments
+    }
+
+    fn users(count: u128) -> impl Iterator<Item = Uuid> {
+        (0..count).map(|i| Uuid::from_u128(i.wrapping_mul(0x9e3779b97f4a7c15f39cc0605cedc835)))
+    }
+
+    #[test]
+    fn bucket_is_stable() {
+        let user = Uuid::from_u128(0x0123456789abcdef0123456789abcdef);
+        assert_eq!(bucket("exp", &user), bucket("exp", &user));
+        // Fixed across releases too, changing the hash would move every user
+        assert_eq!(bucket("exp", &user), 0x7235e37cd20ca9f6);
+        assert_ne!(bucket("exp", &user), bucket("other", &user));
+    }
+
+    #[test]
+    fn variants_follow_their_weights() {
+        let experiment = &group(vec![
+            row("exp", SURFACE_SEARCH, "a", 1, None),
+            row("exp", SURFACE_SEARCH, "b", 3, None),
+            row("exp", SURFACE_SEARCH, "off", 0, None)
+        ])[0];
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        for user in users(20000) {
+            let variant = experiment.variant_of(&user).unwrap();
+            *counts.entry(variant.name.clone()).or_default() += 1;
+        }
+        let share = |name: &str| counts.get(name).copied().unwrap_or(0) as f64 / 20000.0;
+        assert!((share("a") - 0.25).abs() < 0.02, "{:?}", counts);
+        assert!((share("b") - 0.75).abs() < 0.02, "{:?}", counts);
+        assert_eq!(share("off"), 0.0);
+    }
+
+    #[test]
+    fn all_zero_weights_assign_no_variant() {
+        let experiment = &group(vec![
+            row("exp", SURFACE_SEARCH, "a", 0, None),
+            row("exp", SURFACE_SEARCH, "b", 0, None)
+        ])[0];
+        assert!(users(100).all(|u| experiment.variant_of(&u).is_none()));
+    }
+
+    #[test]
+    fn rows_group_into_experiments_and_variants() {
+        let grouped = group(vec![
+            row("a", SURFACE_SEARCH, "control", 1, None),
+            row("a", SURFACE_SEARCH, "test", 2, Some(("rrf_k", 30.0))),
+            row("a", SURFACE_SEARCH, "test", 2, Some(("text_weight", 2.0))),
+            row("b", SURFACE_TOP_STALL, "only", -1, Some(("like_weight", 0.5)))
+        ]);
+        assert_eq!(grouped.len(), 2);
+
+        assert_eq!((grouped[0].id.as_str(), grouped[0].surface.as_str()), ("a", SURFACE_SEARCH));
+        let variants: Vec<(&str, u64, usize)> = grouped[0].variants.iter()
+            .map(|v| (v.name.as_str(), v.weight, v.params.len()))
+            .collect();
+        assert_eq!(variants, vec![("control", 1, 0), ("test", 2, 2)]);
+        assert_eq!(grouped[0].variants[1].params.get("text_weight"), Some(&2.0));
+
+        // Negative weights count as zero
+        assert_eq!(grouped[1].variants[0].weight, 0);
+    }
+
+    #[test]
+    fn greatest_experiment_id_wins_a_clash() {
+        let experiments = experiments(vec![
+            row("a", SURFACE_SEARCH, "only", 1, Some(("rrf_k", 10.0))),
+            row("a", SURFACE_SEARCH, "only", 1, Some(("text_weight", 3.0))),
+            row("b", SURFACE_SEARCH, "only", 1, Some(("rrf_k", 20.0))),
+            row("c", SURFACE_TOP_STALL, "only", 1, Some(("rrf_k", 30.0)))
+        ]);
+        let assignment = experiments.assign(SURFACE_SEARCH, &Uuid::from_u128(7));
+        assert_eq!(assignment.param("rrf_k", 60.0), 20.0);
+        assert_eq!(assignment.param("text_weight", 1.0), 3.0);
+        assert_eq!(assignment.param("vector_weight", 1.0), 1.0);
+        assert_eq!(assignment.variants.len(), 2);
+    }
+
+    #[test]
+    fn experiments_with_unusable_params_are_skipped() {
+        let experiments = experiments(vec![
+            row("a", SURFACE_TOP_STALL, "control", 1, None),
+            row("a", SURFACE_TOP_STALL, "test", 1, Some(("prior_weight", 0.0))),
+            row("b", SURFACE_SEARCH, "test", 1, Some(("rrf_k", -5.0))),
+            row("c", SURFACE_FOR_YOU, "test", 1, Some(("also_liked", -1.0))),
+            row("d", SURFACE_FOR_YOU, "test", 1, Some(("min_score", f64::NAN))),
+            row("e", SURFACE_RECOMMENDATIONS, "test", 1, Some(("min_score", 0.2)))
+        ]);
+        let running: Vec<String> = experiments.running.read().unwrap().iter().map(|e| e.id.clone()).collect();
+        assert_eq!(running, vec!["e".to_string()]);
+    }
+}
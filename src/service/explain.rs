@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use sqlx::types::Uuid;
+use sqlx::{Error, PgPool};
+
+use super::kueater::data::types::{DietCompatibility, Explanation};
+
+#[derive(Debug, sqlx::FromRow)]
+struct ExplanationRow {
+    menu_id: Uuid,
+    cuisine: Option<String>,
+    similar_liked_dish: Option<String>,
+    disliked_ingredients: Vec<String>,
+    diets: Vec<String>,
+    diet_scores: Vec<f64>
+}
+
+// Why each item suits the user or not, keyed by menu uuid as in MenuCardProps.
+// Explanations are extra: on failure cards are sent without them.
+pub async fn explain_items(
+    pg_pool: &PgPool,
+    user_id: &Uuid,
+    menu_ids: &[Uuid]
+) -> HashMap<String, Explanation> {
+    let rows: Result<Vec<ExplanationRow>, Error> = sqlx::query_as(
+        "SELECT * FROM kueater.explain_menuitems($1, $2)"
+    )
+    .bind(user_id)
+    .bind(menu_ids)
+    .fetch_all(pg_pool).await;
+
+    match rows {
+        Ok(rows) => rows.into_iter().map(|r| (
+            r.menu_id.to_string(),
+            Explanation {
+                cuisine: r.cuisine,
+                similar_liked_dish: r.similar_liked_dish,
+                disliked_ingredients: r.disliked_ingredients,
+                diets: r.diets.into_iter().zip(r.diet_scores).map(|(diet, score)| {
+                    DietCompatibility { diet, score }
+                }).collect()
+            }
+        )).collect(),
+        Err(e) => {
+            println!("Cannot explain menu items: {}", e);
+            HashMap::new()
+        }
+    }
+}
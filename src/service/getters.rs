@@ -23,7 +23,8 @@ struct Prefereces {
     #[sqlx(rename = "disliked_ingredients")]
     dislikes: Vec<String>,
     #[sqlx(rename = "favorite_dishes")]
-    likes: Vec<String>
+    likes: Vec<String>,
+    allergen_policy: String
 }
 
 #[derive(Debug, Deserialize, sqlx::FromRow)]
@@ -44,6 +45,21 @@ struct MenuItem {
     saved: bool
 }
 
+#[derive(Debug, sqlx::FromRow)]
+struct MenuItemDetail {
+    cuisine: Option<String>,
+    food_type: Option<String>,
+    ingredient_ids: Vec<Uuid>,
+    ingredient_names: Vec<String>,
+    diets: Vec<String>,
+    diet_scores: Vec<f64>,
+    allergens: Vec<String>,
+    allergen_scores: Vec<f64>,
+    likes: i32,
+    dislikes: i32,
+    saves: i32
+}
+
 #[derive(Debug, Deserialize, sqlx::FromRow)]
 struct Stall {
     uuid: String,
@@ -57,7 +73,9 @@ struct Stall {
     reviews: i32,
     likes: i32,     // Aggregate from menu likes
     rating: f32,
-    saved: bool
+    saved: bool,
+    is_open_now: bool,
+    closes_in_minutes: Option<i32>
 }
 
 pub async fn get_preferences(
@@ -82,7 +100,7 @@ pub async fn get_preferences(
 
     let pref: Result<Prefereces, Error> = sqlx::query_as(
         "
-        SELECT u.id, email, name, gender::text, role::text, diets::text[], allergies::text[], cuisines, disliked_ingredients, favorite_dishes FROM
+        SELECT u.id, email, name, gender::text, role::text, diets::text[], allergies::text[], cuisines, disliked_ingredients, favorite_dishes, allergen_policy::text FROM
         kueater.userprofile u
         JOIN kueater.user_profile_preferences upf ON u.id = upf.user_id
         JOIN kueater.user_preferences up ON upf.preferences_id = up.id
@@ -103,7 +121,11 @@ pub async fn get_preferences(
                     allergies: p.allergies,
                     cuisines: p.cuisines,
                     dislikes: p.dislikes,
-                    likes: p.likes
+                    likes: p.likes,
+                    allergen_policy: match p.allergen_policy.as_str() {
+                        "hide" => AllergenPolicy::Hide,
+                        _ => AllergenPolicy::Warn
+                    }.into()
                 }
             ))
         }
@@ -144,6 +166,17 @@ pub async fn get_menu_item(
     .bind(&user_id)
     .fetch_one(pg_pool).await;
 
+    let explanations = super::explain::explain_items(pg_pool, &user_id, &[menu_id]).await;
+
+    // Asked for by id, so shown whatever the user's policy, with its warning
+    let allergens = match super::allergen::AllergenGuard::load(pg_pool, &user_id, &[menu_id]).await {
+        Ok(res) => res,
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+
     match item {
         Ok(i) => {
             Ok(Response::new(
@@ -164,7 +197,9 @@ pub async fn get_menu_item(
                     reason: i.reason,
                     liked: i.liked,
                     disliked: i.disliked,
-                    saved: i.saved
+                    saved: i.saved,
+                    explanation: explanations.get(&menu_id.to_string()).cloned(),
+                    allergen_warning: allergens.warning(&menu_id.to_string())
                 }
             ))
         }
@@ -175,6 +210,142 @@ pub async fn get_menu_item(
     }
 }
 
+// Detail page of a menu item: its card, ingredients and how it fits the user's diets and allergies
+pub async fn get_menu_item_detail(
+    pg_pool: &PgPool,
+    request: Recv<GetMenuItemDetailRequest>
+) -> Send<GetMenuItemDetailResponse> {
+    let extensions = request.extensions().clone();
+    let data = request.into_inner();
+
+    let user_id = extensions.get::<super::UserContext>().unwrap().user_id.clone();
+
+    let user_id = match user_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("User id not a UUID"));
+        }
+    };
+
+    let menu_id = match data.item_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("Menu id not a UUID"));
+        }
+    };
+
+    let detail: MenuItemDetail = match sqlx::query_as(
+        "SELECT * FROM kueater.menuitem_detail($1, $2)"
+    )
+    .bind(user_id)
+    .bind(menu_id)
+    .fetch_optional(pg_pool).await {
+        Ok(Some(res)) => res,
+        Ok(None) => {
+            return Err(Status::not_found("Menu item not found"));
+        }
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+
+    let item: MenuItem = match sqlx::query_as(
+        "SELECT * FROM kueater.get_menu_card_props($1, $2)"
+    )
+    .bind(menu_id)
+    .bind(user_id)
+    .fetch_one(pg_pool).await {
+        Ok(res) => res,
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Cannot get menu item"));
+        }
+    };
+
+    // Items not sold by any stall have no stall to show
+    let stall: Option<Stall> = match item.stall_id.parse::<Uuid>() {
+        Ok(stall_id) => match sqlx::query_as(
+            "SELECT * FROM kueater.get_stall_data_props($1, $2)"
+        )
+        .bind(stall_id)
+        .bind(user_id)
+        .fetch_optional(pg_pool).await {
+            Ok(res) => res,
+            Err(e) => {
+                println!("{}", e);
+                return Err(Status::internal("Database failure"));
+            }
+        },
+        Err(_) => None
+    };
+
+    let explanations = super::explain::explain_items(pg_pool, &user_id, &[menu_id]).await;
+
+    let allergens = match super::allergen::AllergenGuard::load(pg_pool, &user_id, &[menu_id]).await {
+        Ok(res) => res,
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+
+    Ok(Response::new(GetMenuItemDetailResponse {
+        ratings: Some(MenuItemRatings {
+            likes: detail.likes,
+            dislikes: detail.dislikes,
+            saves: detail.saves,
+            stall_rating: stall.as_ref().map_or(0.0, |s| s.rating),
+            stall_reviews: stall.as_ref().map_or(0, |s| s.reviews)
+        }),
+        stall: stall.map(|s| types::StallDataTypeProps {
+            uuid: s.uuid,
+            name: s.name,
+            rank: s.rank,
+            image_url: s.image_url,
+            location: s.location,
+            operating_hours: s.operating_hours,
+            price_range: s.price_range,
+            tags: s.tags,
+            reviews: s.reviews,
+            likes: s.likes,
+            rating: s.rating,
+            saved: s.saved,
+            is_open_now: s.is_open_now,
+            closes_in_minutes: s.closes_in_minutes
+        }),
+        card: Some(types::MenuCardProps {
+            explanation: explanations.get(&item.uuid).cloned(),
+            allergen_warning: allergens.warning(&item.uuid),
+            uuid: item.uuid,
+            name: item.name,
+            price: item.price,
+            likes: item.likes,
+            dislikes: item.dislikes,
+            stall_id: item.stall_id,
+            stall_name: item.stall_name,
+            stall_lock: item.stall_lock,
+            image_url: item.image_url,
+            score: item.score.map(|v| v as f32),
+            reason: item.reason,
+            liked: item.liked,
+            disliked: item.disliked,
+            saved: item.saved
+        }),
+        cuisine: detail.cuisine,
+        food_type: detail.food_type,
+        ingredients: detail.ingredient_ids.into_iter().zip(detail.ingredient_names).map(|(id, name)| {
+            MenuIngredient { uuid: id.to_string(), name }
+        }).collect(),
+        diets: detail.diets.into_iter().zip(detail.diet_scores).map(|(diet, score)| {
+            types::DietCompatibility { diet, score }
+        }).collect(),
+        allergens: detail.allergens.into_iter().zip(detail.allergen_scores).map(|(allergen, score)| {
+            types::AllergenExposure { allergen, score }
+        }).collect()
+    }))
+}
+
 pub async fn get_stall(
     pg_pool: &PgPool,
     request: Recv<GetStallRequest>
@@ -220,7 +391,9 @@ pub async fn get_stall(
                     reviews: s.reviews,
                     likes: s.likes,
                     rating: s.rating,
-                    saved: s.saved
+                    saved: s.saved,
+                    is_open_now: s.is_open_now,
+                    closes_in_minutes: s.closes_in_minutes
                 }
             ))
         }
@@ -269,6 +442,15 @@ pub async fn items_in_stall(
         }
     }
 
+    let allergens = match super::allergen::AllergenGuard::load(pg_pool, &user_id, &items_to_query).await {
+        Ok(res) => res,
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+    items_to_query.retain(|id| allergens.allows(id));
+
     let conversions: Vec<MenuItem> = stream::iter(&items_to_query)
         .then(|uuid| async move {
             let item: MenuItem = sqlx::query_as(
@@ -280,6 +462,8 @@ pub async fn items_in_stall(
             return item
         }).collect().await;
     
+    let explanations = super::explain::explain_items(pg_pool, &user_id, &items_to_query).await;
+
     Ok(Response::new(
         types::MenuCardGridConstructor {
             data: conversions.iter().map(|i| types::MenuCardProps {
@@ -299,7 +483,9 @@ pub async fn items_in_stall(
                 reason: i.reason.clone(),
                 liked: i.liked,
                 disliked: i.disliked,
-                saved: i.saved
+                saved: i.saved,
+                explanation: explanations.get(&i.uuid).cloned(),
+                allergen_warning: allergens.warning(&i.uuid)
             }).collect()
         }
     ))
@@ -0,0 +1,274 @@
+use futures::{stream, StreamExt};
+use serde::Deserialize;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use tonic::{Response, Status};
+
+use crate::{AgentCommand, Command, service::backend::AgentCommandSender};
+
+use super::backend::{Send, Recv};
+use super::kueater::data::types;
+use super::kueater::{Empty, data::*, data::activity::*};
+
+#[derive(Debug, Deserialize, sqlx::FromRow)]
+struct MenuItem {
+    uuid: String,
+    name: String,
+    price: f64,
+    likes: i32,
+    dislikes: i32,
+    stall_id: String,
+    stall_name: String,
+    stall_lock: String,
+    image_url: String,
+    score: Option<f64>,
+    reason: Option<String>,
+    liked: bool,
+    disliked: bool,
+    saved: bool
+}
+
+#[derive(Debug, Deserialize, sqlx::FromRow)]
+struct Stall {
+    uuid: String,
+    rank: i32,
+    name: String,
+    image_url: String,
+    location: String,
+    operating_hours: String,
+    price_range: String,
+    tags: String,
+    reviews: i32,
+    likes: i32,     // Aggregate from menu likes
+    rating: f32,
+    saved: bool,
+    is_open_now: bool,
+    closes_in_minutes: Option<i32>
+}
+
+// Hidden items and stalls leave every personalized surface, see migration 0026.
+// The agent is told as well, so it can take them into account when scoring.
+async fn send_feedback(
+    sender: &AgentCommandSender,
+    user_id: Uuid,
+    object_type: &str,
+    object_id: Uuid,
+    hidden: bool
+) {
+    let command = AgentCommand {
+        msg: Command::Feedback {
+            user_id: user_id.to_string(),
+            object_type: object_type.to_string(),
+            object_id: object_id.to_string(),
+            hidden
+        },
+        tx: None
+    };
+    if sender.send(command).await.is_err() {
+        println!("Cannot send feedback to agent");
+    }
+}
+
+// Hiding an id missing from the catalog fails the foreign key of kueater.hidden_item / hidden_stall
+fn is_foreign_key_violation(e: &sqlx::Error) -> bool {
+    e.as_database_error().is_some_and(|d| d.is_foreign_key_violation())
+}
+
+pub async fn hide_item(
+    pg_pool: &PgPool,
+    request: Recv<HideItemMsg>,
+    sender: &AgentCommandSender
+) -> Send<Empty> {
+
+    let extensions = request.extensions().clone();
+
+    let user_id = extensions.get::<super::UserContext>().unwrap().user_id.clone();
+
+    let user_id = match user_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("User id not a UUID"));
+        }
+    };
+
+    let data = request.into_inner();
+
+    let menu_id = match data.item_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("Menu id not a UUID"));
+        }
+    };
+
+    match sqlx::query("SELECT kueater.toggle_hide_menu($1, $2, $3)")
+    .bind(user_id).bind(menu_id).bind(data.b).execute(pg_pool).await {
+        Ok(_) => {
+            send_feedback(sender, user_id, "menuitem", menu_id, data.b).await;
+        }
+        Err(e) if is_foreign_key_violation(&e) => {
+            return Err(Status::not_found("Menu item not found"));
+        }
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    }
+
+    Ok(Response::new(Empty {  }))
+}
+
+pub async fn hide_stall(
+    pg_pool: &PgPool,
+    request: Recv<HideStallMsg>,
+    sender: &AgentCommandSender
+) -> Send<Empty> {
+
+    let extensions = request.extensions().clone();
+
+    let user_id = extensions.get::<super::UserContext>().unwrap().user_id.clone();
+
+    let user_id = match user_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("User id not a UUID"));
+        }
+    };
+
+    let data = request.into_inner();
+
+    let stall_id = match data.stall_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("Stall id not a UUID"));
+        }
+    };
+
+    match sqlx::query("SELECT kueater.toggle_hide_stall($1, $2, $3)")
+    .bind(user_id).bind(stall_id).bind(data.b).execute(pg_pool).await {
+        Ok(_) => {
+            send_feedback(sender, user_id, "stall", stall_id, data.b).await;
+        }
+        Err(e) if is_foreign_key_violation(&e) => {
+            return Err(Status::not_found("Stall not found"));
+        }
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    }
+
+    Ok(Response::new(Empty {  }))
+}
+
+// Everything the user hid, latest first, so they can take it back with HideItem / HideStall
+pub async fn hidden_items(
+    pg_pool: &PgPool,
+    request: Recv<HiddenItemsRequest>
+) -> Send<HiddenItemsResponse> {
+
+    let extensions = request.extensions().clone();
+
+    let user_id = extensions.get::<super::UserContext>().unwrap().user_id.clone();
+
+    let user_id = match user_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("User id not a UUID"));
+        }
+    };
+
+    let items_to_query: Vec<Uuid> = match sqlx::query_as::<_, (Uuid,)>(
+        "
+        SELECT menu_id FROM kueater.hidden_item WHERE user_id = $1
+        ORDER BY created_at DESC
+        "
+    ).bind(user_id).fetch_all(pg_pool).await {
+        Ok(rows) => rows.iter().map(|(i,)|*i).collect(),
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+
+    let stalls_to_query: Vec<Uuid> = match sqlx::query_as::<_, (Uuid,)>(
+        "
+        SELECT stall_id FROM kueater.hidden_stall WHERE user_id = $1
+        ORDER BY created_at DESC
+        "
+    ).bind(user_id).fetch_all(pg_pool).await {
+        Ok(rows) => rows.iter().map(|(i,)|*i).collect(),
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+
+    let conversions: Vec<MenuItem> = stream::iter(&items_to_query)
+        .then(|uuid| async move {
+            let item: MenuItem = sqlx::query_as(
+                "SELECT * FROM kueater.get_menu_card_props($1, $2)"
+            )
+            .bind(uuid)
+            .bind(user_id)
+            .fetch_one(pg_pool).await.unwrap();
+            item
+        }).collect().await;
+
+    let stall_conversions: Vec<Stall> = stream::iter(&stalls_to_query)
+        .then(|uuid| async move {
+            let stall: Stall = sqlx::query_as(
+                "SELECT * FROM kueater.get_stall_data_props($1, $2)"
+            )
+            .bind(uuid)
+            .bind(user_id)
+            .fetch_one(pg_pool).await.unwrap();
+            stall
+        }).collect().await;
+
+    let allergens = match super::allergen::AllergenGuard::load(pg_pool, &user_id, &items_to_query).await {
+        Ok(res) => res,
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+
+    Ok(Response::new(
+        HiddenItemsResponse {
+            menus: conversions.iter().map(|i| types::MenuCardProps {
+                uuid: i.uuid.clone(),
+                name: i.name.clone(),
+                price: i.price,
+                likes: i.likes,
+                dislikes: i.dislikes,
+                stall_id: i.stall_id.clone(),
+                stall_name: i.stall_name.clone(),
+                stall_lock: i.stall_lock.clone(),
+                image_url: i.image_url.clone(),
+                score: i.score.map(|v| v as f32),
+                reason: i.reason.clone(),
+                liked: i.liked,
+                disliked: i.disliked,
+                saved: i.saved,
+                explanation: None,
+                allergen_warning: allergens.warning(&i.uuid)
+            }).collect(),
+            stalls: stall_conversions.iter().map(|s| types::StallDataTypeProps {
+                uuid: s.uuid.clone(),
+                name: s.name.clone(),
+                rank: s.rank,
+                image_url: s.image_url.clone(),
+                location: s.location.clone(),
+                operating_hours: s.operating_hours.clone(),
+                price_range: s.price_range.clone(),
+                tags: s.tags.clone(),
+                reviews: s.reviews,
+                likes: s.likes,
+                rating: s.rating,
+                saved: s.saved,
+                is_open_now: s.is_open_now,
+                closes_in_minutes: s.closes_in_minutes
+            }).collect()
+        }
+    ))
+}
@@ -0,0 +1,177 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use sqlx::types::{Decimal, Uuid};
+
+use super::super::env_or;
+
+// Bumped whenever the cursor layout changes, older cursors are then refused
+const CURSOR_VERSION: u32 = 2;
+
+// Where the next page of recommendations starts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "k")]
+pub enum Cursor {
+    // After (score, id) in the (score DESC, id ASC) order of the agent's scores,
+    // within the scoring generation the first page was read from
+    #[serde(rename = "rec")]
+    Recommendation {
+        #[serde(with = "decimal_string")]
+        score: Decimal,
+        id: i32,
+        generation: i64
+    },
+    // After the menu item id, for accounts paging the whole menu
+    #[serde(rename = "idx")]
+    DatabaseIndex { id: Uuid },
+    // Offset into the fallback ranking
+    #[serde(rename = "fb")]
+    Fallback { offset: usize }
+}
+
+// Scores are kept exact as strings, a float could land between two stored scores
+mod decimal_string {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use sqlx::types::Decimal;
+
+    pub fn serialize<S: Serializer>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    v: u32,
+    exp: u64,
+    #[serde(flatten)]
+    cursor: Cursor
+}
+
+// Index tokens are opaque to clients: an HS256 JWT over the cursor, so they cannot be forged
+// to page from arbitrary positions. Without CURSOR_SECRET a random key is used, and tokens
+// stop working when the server restarts.
+#[derive(Clone)]
+pub struct CursorCodec {
+    secret: Vec<u8>,
+    ttl: Duration
+}
+
+impl fmt::Debug for CursorCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CursorCodec").field("ttl", &self.ttl).finish_non_exhaustive()
+    }
+}
+
+impl CursorCodec {
+    pub fn from_env() -> Self {
+        let secret = match std::env::var("CURSOR_SECRET") {
+            Ok(s) if !s.is_empty() => s.into_bytes(),
+            _ => {
+                println!("CURSOR_SECRET is not set, index tokens will not survive a restart");
+                Uuid::new_v4().as_bytes().to_vec()
+            }
+        };
+        Self {
+            secret,
+            ttl: Duration::from_secs(env_or("CURSOR_TTL_SECS", 86400))
+        }
+    }
+
+    pub fn encode(&self, cursor: Cursor) -> String {
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default() + self.ttl;
+        let claims = Claims { v: CURSOR_VERSION, exp: exp.as_secs(), cursor };
+        // Serializing a plain struct with HS256 cannot fail
+        encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(&self.secret))
+            .unwrap_or_default()
+    }
+
+    // None for tokens that are malformed, tampered with, expired or from another version
+    pub fn decode(&self, token: &str) -> Option<Cursor> {
+        let validation = Validation::new(Algorithm::HS256);
+        let claims = decode::<Claims>(token, &DecodingKey::from_secret(&self.secret), &validation)
+            .ok()?
+            .claims;
+        if claims.v != CURSOR_VERSION {
+            return None;
+        }
+        Some(claims.cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec(secret: &str) -> CursorCodec {
+        CursorCodec { secret: secret.as_bytes().to_vec(), ttl: Duration::from_secs(60) }
+    }
+
+    fn token_with(secret: &str, v: u32, exp: u64) -> String {
+        let claims = Claims { v, exp, cursor: Cursor::Fallback { offset: 3 } };
+        encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        let codec = codec("secret");
+        let cursors = [
+            Cursor::Recommendation { score: "0.8123456789012345678901234".parse().unwrap(), id: 42, generation: 7 },
+            Cursor::Recommendation { score: Decimal::ZERO, id: i32::MAX, generation: 0 },
+            Cursor::DatabaseIndex { id: Uuid::new_v4() },
+            Cursor::Fallback { offset: 0 },
+            Cursor::Fallback { offset: 200 }
+        ];
+        for cursor in cursors {
+            assert_eq!(codec.decode(&codec.encode(cursor.clone())), Some(cursor));
+        }
+    }
+
+    #[test]
+    fn tampered_token_is_refused() {
+        let codec = codec("secret");
+        let token = codec.encode(Cursor::Fallback { offset: 100 });
+        let forged = codec.encode(Cursor::Fallback { offset: 5000 });
+
+        // Claims of one token under the signature of another
+        let (_, signature) = token.rsplit_once('.').unwrap();
+        let (payload, _) = forged.rsplit_once('.').unwrap();
+        assert_eq!(codec.decode(&format!("{}.{}", payload, signature)), None);
+
+        // Away from the last character, whose low bits the signature does not use
+        let mut flipped = token.into_bytes();
+        let at = flipped.len() - 10;
+        flipped[at] = if flipped[at] == b'A' { b'B' } else { b'A' };
+        assert_eq!(codec.decode(&String::from_utf8(flipped).unwrap()), None);
+
+        assert_eq!(codec.decode("not a token"), None);
+    }
+
+    #[test]
+    fn token_signed_with_another_key_is_refused() {
+        let token = codec("secret").encode(Cursor::Fallback { offset: 100 });
+        assert_eq!(codec("another secret").decode(&token), None);
+    }
+
+    #[test]
+    fn token_of_another_version_is_refused() {
+        let codec = codec("secret");
+        assert_eq!(codec.decode(&token_with("secret", CURSOR_VERSION, now() + 60)), Some(Cursor::Fallback { offset: 3 }));
+        assert_eq!(codec.decode(&token_with("secret", CURSOR_VERSION - 1, now() + 60)), None);
+        assert_eq!(codec.decode(&token_with("secret", CURSOR_VERSION + 1, now() + 60)), None);
+    }
+
+    #[test]
+    fn expired_token_is_refused() {
+        assert_eq!(codec("secret").decode(&token_with("secret", CURSOR_VERSION, now() - 3600)), None);
+    }
+}
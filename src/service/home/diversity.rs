@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use sqlx::types::Uuid;
+use sqlx::{Error, PgPool};
+
+use super::super::env_or;
+
+// Tunables for maximal marginal relevance re-ranking, see rerank.
+#[derive(Debug, Clone)]
+pub struct DiversityConfig {
+    pub lambda: f64,            // 1 keeps the relevance order, 0 only looks for variety
+    pub stall_weight: f64,
+    pub cuisine_weight: f64,
+    pub embedding_weight: f64
+}
+
+impl DiversityConfig {
+    pub fn from_env() -> Self {
+        Self {
+            lambda: env_or("DIVERSITY_LAMBDA", 0.7),
+            stall_weight: env_or("DIVERSITY_STALL_WEIGHT", 1.0),
+            cuisine_weight: env_or("DIVERSITY_CUISINE_WEIGHT", 0.5),
+            embedding_weight: env_or("DIVERSITY_EMBEDDING_WEIGHT", 1.0)
+        }
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct Features {
+    menu_id: Uuid,
+    stall_id: Option<Uuid>,
+    cuisine: Option<String>
+}
+
+// Similarity of two items in [0, 1]: same stall, same cuisine and embedding similarity,
+// weighted by the config. Unknown stalls, cuisines and embeddings never count as similar.
+struct Similarity<'a> {
+    config: &'a DiversityConfig,
+    features: HashMap<Uuid, Features>,
+    embedding: HashMap<(Uuid, Uuid), f64>
+}
+
+impl Similarity<'_> {
+    fn between(&self, a: &Uuid, b: &Uuid) -> f64 {
+        let total = self.config.stall_weight + self.config.cuisine_weight + self.config.embedding_weight;
+        if total <= 0.0 {
+            return 0.0;
+        }
+
+        let mut sim = 0.0;
+        if let (Some(fa), Some(fb)) = (self.features.get(a), self.features.get(b)) {
+            if fa.stall_id.is_some() && fa.stall_id == fb.stall_id {
+                sim += self.config.stall_weight;
+            }
+            if fa.cuisine.is_some() && fa.cuisine == fb.cuisine {
+                sim += self.config.cuisine_weight;
+            }
+        }
+        let key = if a < b { (*a, *b) } else { (*b, *a) };
+        if let Some(e) = self.embedding.get(&key) {
+            sim += self.config.embedding_weight * e.clamp(0.0, 1.0);
+        }
+        sim / total
+    }
+}
+
+// Maximal marginal relevance: picks up to k items, each time the one maximizing
+//      lambda * relevance(i) - (1 - lambda) * max similarity(i, already picked)
+// Relevance is rescaled to [0, 1] over the candidates, so any score works.
+// Candidates are expected best first; ties keep that order.
+pub async fn rerank(
+    pg_pool: &PgPool,
+    config: &DiversityConfig,
+    candidates: &[(Uuid, f64)],
+    k: usize
+) -> Result<Vec<Uuid>, Error> {
+    if config.lambda >= 1.0 || candidates.len() <= 1 {
+        return Ok(candidates.iter().take(k).map(|(i, _)| *i).collect());
+    }
+
+    let ids: Vec<Uuid> = candidates.iter().map(|(i, _)| *i).collect();
+
+    let features: Vec<Features> = sqlx::query_as(
+        "SELECT * FROM kueater.menuitem_diversity_features($1)"
+    )
+    .bind(&ids)
+    .fetch_all(pg_pool).await?;
+
+    let embedding: Vec<(Uuid, Uuid, f64)> = sqlx::query_as(
+        "SELECT * FROM kueater.menuitem_pairwise_similarity($1)"
+    )
+    .bind(&ids)
+    .fetch_all(pg_pool).await?;
+
+    let similarity = Similarity {
+        config,
+        features: features.into_iter().map(|f| (f.menu_id, f)).collect(),
+        embedding: embedding.into_iter().map(|(a, b, s)| ((a, b), s)).collect()
+    };
+
+    let max = candidates.iter().map(|(_, r)| *r).fold(f64::MIN, f64::max);
+    let min = candidates.iter().map(|(_, r)| *r).fold(f64::MAX, f64::min);
+    let relevance = |r: f64| if max > min { (r - min) / (max - min) } else { 1.0 };
+
+    // Highest similarity of each remaining candidate to the picked items
+    let mut remaining: Vec<(Uuid, f64, f64)> = candidates.iter()
+        .map(|(i, r)| (*i, relevance(*r), 0.0))
+        .collect();
+    let mut picked: Vec<Uuid> = Vec::with_capacity(k.min(candidates.len()));
+
+    while picked.len() < k && !remaining.is_empty() {
+        let mut best = 0;
+        let mut best_value = f64::MIN;
+        for (idx, (_, rel, max_sim)) in remaining.iter().enumerate() {
+            let value = config.lambda * rel - (1.0 - config.lambda) * max_sim;
+            if value > best_value {
+                best = idx;
+                best_value = value;
+            }
+        }
+
+        let (chosen, _, _) = remaining.remove(best);
+        for (id, _, max_sim) in remaining.iter_mut() {
+            *max_sim = f64::max(*max_sim, similarity.between(id, &chosen));
+        }
+        picked.push(chosen);
+    }
+
+    Ok(picked)
+}
@@ -0,0 +1,148 @@
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use sqlx::types::Uuid;
+use sqlx::{Error, PgPool};
+
+use super::super::env_or;
+
+// Favourite dish similarity from which the item counts as that dish, as in kueater.menuitem_score_reasons
+const DISH_MATCH_SIMILARITY: f64 = 0.6;
+
+// Tunables for ranking items before the agent has scored the user, see FallbackItem::score.
+#[derive(Debug, Clone)]
+pub struct FallbackWeights {
+    pub cuisine: f64,
+    pub dish: f64,          // Times the favourite dish similarity
+    pub disliked: f64,      // Penalty per disliked ingredient
+    pub diet: f64,          // Times the diet score, items of unknown diet get half
+    pub popularity: f64     // Times the popularity relative to the most popular item
+}
+
+impl FallbackWeights {
+    pub fn from_env() -> Self {
+        Self {
+            cuisine: env_or("FALLBACK_CUISINE_WEIGHT", 1.0),
+            dish: env_or("FALLBACK_DISH_WEIGHT", 2.0),
+            disliked: env_or("FALLBACK_DISLIKED_PENALTY", 1.5),
+            diet: env_or("FALLBACK_DIET_WEIGHT", 0.5),
+            popularity: env_or("FALLBACK_POPULARITY_WEIGHT", 1.0)
+        }
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct FallbackItem {
+    pub menu_id: Uuid,
+    cuisine_match: bool,
+    matched_dish: Option<String>,
+    dish_similarity: f64,
+    disliked_ingredients: i32,
+    diet_score: Option<f64>,
+    diet_conflict: bool,
+    allergen_conflict: bool,
+    likes: i32,
+    dislikes: i32,
+    saves: i32
+}
+
+impl FallbackItem {
+    fn popularity(&self) -> f64 {
+        ((self.likes + self.saves - self.dislikes).max(0) as f64).ln_1p()
+    }
+
+    fn score(&self, weights: &FallbackWeights, max_popularity: f64) -> f64 {
+        let mut score = weights.dish * self.dish_similarity
+            - weights.disliked * self.disliked_ingredients as f64
+            + weights.diet * self.diet_score.unwrap_or(0.5);
+        if self.cuisine_match {
+            score += weights.cuisine;
+        }
+        if max_popularity > 0.0 {
+            score += weights.popularity * self.popularity() / max_popularity;
+        }
+        score
+    }
+
+    // Short sentence shown on the card in place of the agent's reasoning
+    pub fn reason(&self) -> String {
+        match &self.matched_dish {
+            Some(dish) if self.dish_similarity >= DISH_MATCH_SIMILARITY => {
+                format!("Similar to {}, one of your favourite dishes", dish)
+            }
+            _ if self.cuisine_match => "From a cuisine you like".to_string(),
+            _ if self.likes > 0 => format!("Liked by {} {}", self.likes, if self.likes == 1 { "person" } else { "people" }),
+            _ => "Picked for your preferences".to_string()
+        }
+    }
+}
+
+// Every item the user may be shown with its score, best first. Items conflicting with
+// the user's diets or allergens are left out, as they are from the agent's recommendations,
+// and so are items the user hid.
+async fn rank(
+    pg_pool: &PgPool,
+    user_id: &Uuid,
+    weights: &FallbackWeights
+) -> Result<Vec<(f64, FallbackItem)>, Error> {
+    let items: Vec<FallbackItem> = sqlx::query_as(
+        "
+        SELECT f.* FROM kueater.fallback_menuitem_features($1) f
+        WHERE NOT kueater.user_hidden_menuitem($1, f.menu_id)
+        "
+    )
+    .bind(user_id)
+    .fetch_all(pg_pool).await?;
+
+    let max_popularity = items.iter().map(|i| i.popularity()).fold(0.0, f64::max);
+
+    let mut scored: Vec<(f64, FallbackItem)> = items.into_iter()
+        .filter(|i| !i.diet_conflict && !i.allergen_conflict)
+        .map(|i| (i.score(weights, max_popularity), i))
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.menu_id.cmp(&b.1.menu_id)));
+
+    Ok(scored)
+}
+
+pub type Ranking = Arc<Vec<(f64, FallbackItem)>>;
+
+// Rankings by user, so that paging through them scores the catalogue once rather than on
+// every page. The first page always ranks anew, later ones reuse it until older than the TTL.
+#[derive(Debug)]
+pub struct FallbackRanker {
+    weights: FallbackWeights,
+    ttl: Duration,
+    rankings: Mutex<LruCache<Uuid, (Instant, Ranking)>>
+}
+
+impl FallbackRanker {
+    pub fn from_env() -> Self {
+        let capacity = NonZeroUsize::new(env_or("FALLBACK_RANK_CACHE_CAPACITY", 1000))
+            .unwrap_or(NonZeroUsize::MIN);
+        Self {
+            weights: FallbackWeights::from_env(),
+            ttl: Duration::from_secs(env_or("FALLBACK_RANK_CACHE_TTL_SECS", 300)),
+            rankings: Mutex::new(LruCache::new(capacity))
+        }
+    }
+
+    pub async fn rank(&self, pg_pool: &PgPool, user_id: &Uuid, first_page: bool) -> Result<Ranking, Error> {
+        if !first_page {
+            let mut rankings = self.rankings.lock().unwrap();
+            match rankings.get(user_id) {
+                Some((ranked_at, ranking)) if ranked_at.elapsed() < self.ttl => return Ok(ranking.clone()),
+                Some(_) => {
+                    rankings.pop(user_id);
+                }
+                None => {}
+            }
+        }
+
+        let ranking: Ranking = Arc::new(rank(pg_pool, user_id, &self.weights).await?);
+        self.rankings.lock().unwrap().put(*user_id, (Instant::now(), ranking.clone()));
+        Ok(ranking)
+    }
+}
@@ -0,0 +1,152 @@
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use tonic::{Extensions, Request, Response, Status};
+
+use super::super::backend::{Send, Recv};
+use super::super::experiments::Experiments;
+use super::super::kueater::data::types::{MenuCardHorizontalConstructor, StallCardListConstructor};
+use super::super::kueater::data::home::*;
+use super::super::ranking::StallRankConfig;
+use super::{cursor, diversity, fallback};
+
+// The rails reuse their own handlers, each getting the caller's extensions for the user context
+fn sub_request<T>(extensions: &Extensions, message: T) -> Recv<T> {
+    let mut request = Request::new(message);
+    *request.extensions_mut() = extensions.clone();
+    request
+}
+
+fn menu_rail(
+    id: &str,
+    layout: HomeRailLayout,
+    personalized: bool,
+    anchor: Option<String>,
+    props: Result<Option<MenuCardHorizontalConstructor>, Status>
+) -> Option<HomeRail> {
+    match props {
+        Ok(Some(menus)) if !menus.menus.is_empty() => Some(HomeRail {
+            id: id.to_string(),
+            layout: layout.into(),
+            personalized,
+            anchor,
+            menus: Some(menus),
+            stalls: None
+        }),
+        Ok(_) => None,
+        Err(e) => {
+            // A failing rail is left out rather than failing the whole home page
+            println!("Cannot build {} rail: {}", id, e.message());
+            None
+        }
+    }
+}
+
+fn stall_rail(id: &str, props: Result<Option<StallCardListConstructor>, Status>) -> Option<HomeRail> {
+    match props {
+        Ok(Some(stalls)) if !stalls.data.is_empty() => Some(HomeRail {
+            id: id.to_string(),
+            layout: HomeRailLayout::StallList.into(),
+            personalized: false,
+            anchor: None,
+            menus: None,
+            stalls: Some(stalls)
+        }),
+        Ok(_) => None,
+        Err(e) => {
+            println!("Cannot build {} rail: {}", id, e.message());
+            None
+        }
+    }
+}
+
+// The whole home page in one call. Rails are fetched concurrently and ordered by the server:
+// scored accounts lead with For You and "Because You Like X", fresh accounts get no
+// personalized rails, only what is trending.
+pub async fn get_home_feed(
+    pg_pool: &PgPool,
+    fallback: &fallback::FallbackRanker,
+    diversity_config: &diversity::DiversityConfig,
+    cursors: &cursor::CursorCodec,
+    stall_rank_config: &StallRankConfig,
+    experiments: &Experiments,
+    request: Recv<GetHomeFeedMsg>
+) -> Send<HomeFeed> {
+
+    let extensions = request.extensions().clone();
+    let data = request.into_inner();
+
+    let user_id = extensions.get::<super::super::UserContext>().unwrap().user_id.clone();
+
+    let user_id = match user_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("User id not a UUID"));
+        }
+    };
+
+    let scored = super::has_recommendations(pg_pool, &user_id).await?;
+
+    // Picked before the rails are fetched, the InferLike rail is built around it
+    let anchor: Option<String> = if scored {
+        match sqlx::query_as::<_, (Option<String>,)>(
+            "SELECT kueater.home_feed_anchor($1)"
+        ).bind(user_id).fetch_one(pg_pool).await {
+            Ok((anchor,)) => anchor,
+            Err(e) => {
+                println!("{}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let top_menu = super::top_menu(
+        pg_pool, diversity_config, sub_request(&extensions, TopMenuMsg { window: data.window })
+    );
+    let top_stall = super::top_stall(
+        pg_pool, stall_rank_config, experiments, sub_request(&extensions, TopStallMsg { open_only: false })
+    );
+    let for_you = async {
+        if scored {
+            super::for_you(
+                pg_pool, fallback, diversity_config, cursors, experiments,
+                sub_request(&extensions, ForYouMsg { user_id: String::new() })
+            ).await.map(|r| r.into_inner().props)
+        } else {
+            Ok(None)
+        }
+    };
+    let infer_like = async {
+        match &anchor {
+            Some(word) => super::infer_like(
+                pg_pool, diversity_config,
+                sub_request(&extensions, InferLikeMsg { user_id: String::new(), word: word.clone() })
+            ).await.map(|r| r.into_inner().props),
+            None => Ok(None)
+        }
+    };
+
+    let (top_menu, top_stall, for_you, infer_like) = futures::join!(top_menu, top_stall, for_you, infer_like);
+
+    let top_menu = menu_rail(
+        "top_menu", HomeRailLayout::Carousel, false, None, top_menu.map(|r| r.into_inner().props)
+    );
+    let top_stall = stall_rail("top_stall", top_stall.map(|r| r.into_inner().props));
+    let for_you = menu_rail(
+        "for_you", HomeRailLayout::Featured, true, None, for_you
+    );
+    let infer_like = menu_rail(
+        "infer_like", HomeRailLayout::Carousel, true, anchor, infer_like
+    );
+
+    let rails = if scored {
+        vec![for_you, infer_like, top_menu, top_stall]
+    } else {
+        vec![top_menu, top_stall]
+    };
+
+    Ok(Response::new(HomeFeed {
+        rails: rails.into_iter().flatten().collect()
+    }))
+}
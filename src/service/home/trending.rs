@@ -0,0 +1,54 @@
+use sqlx::types::Uuid;
+
+use super::super::kueater::data::home::TrendingWindow;
+
+// Hours until an activity counts half as much as a fresh one
+pub const HALF_LIFE_HOURS: f64 = 72.0;
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct TrendingRow {
+    pub menu_id: Uuid,
+    pub likes: i32,
+    pub saves: i32,
+    pub reviews: i32
+}
+
+// Window name understood by kueater.trending_menu
+pub fn window_param(window: TrendingWindow) -> &'static str {
+    match window {
+        TrendingWindow::Today => "today",
+        TrendingWindow::Week => "week",
+        TrendingWindow::AllTime => "all"
+    }
+}
+
+pub fn title(window: TrendingWindow) -> String {
+    match window {
+        TrendingWindow::Today => "Trending Today".to_string(),
+        TrendingWindow::Week => "Trending This Week".to_string(),
+        TrendingWindow::AllTime => "Top Menus of People".to_string()
+    }
+}
+
+// Short sentence shown on the card, naming the signal that ranked the item.
+pub fn reason(row: &TrendingRow, window: TrendingWindow) -> String {
+    let suffix = match window {
+        TrendingWindow::Today => " today",
+        TrendingWindow::Week => " this week",
+        TrendingWindow::AllTime => ""
+    };
+
+    if row.likes > 0 && row.likes >= row.saves {
+        format!("{} {}{}", row.likes, plural(row.likes, "like", "likes"), suffix)
+    } else if row.saves > 0 {
+        format!("Saved {} {}{}", row.saves, plural(row.saves, "time", "times"), suffix)
+    } else if row.reviews > 0 {
+        format!("Stall reviewed {} {}{}", row.reviews, plural(row.reviews, "time", "times"), suffix)
+    } else {
+        format!("Popular{}", suffix)
+    }
+}
+
+fn plural<'a>(n: i32, one: &'a str, many: &'a str) -> &'a str {
+    if n == 1 { one } else { many }
+}
@@ -0,0 +1,304 @@
+use futures::{stream, StreamExt};
+use serde::Deserialize;
+use sqlx::types::Uuid;
+use sqlx::{Error, PgPool};
+use tonic::{Response, Status};
+
+use super::backend::{Send, Recv};
+use super::kueater::data::types;
+use super::kueater::data::location::*;
+
+const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+
+#[derive(Debug, Deserialize, sqlx::FromRow)]
+struct CanteenRow {
+    id: Uuid,
+    name: String,
+    building: Option<String>,
+    latitude: f64,
+    longitude: f64,
+    floors: i32,
+    stall_count: i64
+}
+
+#[derive(Debug, Deserialize, sqlx::FromRow)]
+struct Stall {
+    uuid: String,
+    rank: i32,
+    name: String,
+    image_url: String,
+    location: String,
+    operating_hours: String,
+    price_range: String,
+    tags: String,
+    reviews: i32,
+    likes: i32,     // Aggregate from menu likes
+    rating: f32,
+    saved: bool,
+    is_open_now: bool,
+    closes_in_minutes: Option<i32>
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct StallPosition {
+    stall_id: Uuid,
+    canteen_id: Uuid,
+    canteen_name: String,
+    floor: Option<i32>,
+    latitude: f64,
+    longitude: f64
+}
+
+// Great-circle distance between two coordinates in degrees
+pub fn haversine_meters(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * a.sqrt().asin()
+}
+
+fn to_canteen(c: &CanteenRow) -> Canteen {
+    Canteen {
+        uuid: c.id.to_string(),
+        name: c.name.clone(),
+        building: c.building.clone().unwrap_or_default(),
+        latitude: c.latitude,
+        longitude: c.longitude,
+        floors: c.floors,
+        stall_count: c.stall_count as i32
+    }
+}
+
+fn to_stall_props(s: &Stall) -> types::StallDataTypeProps {
+    types::StallDataTypeProps {
+        uuid: s.uuid.clone(),
+        name: s.name.clone(),
+        rank: s.rank,
+        image_url: s.image_url.clone(),
+        location: s.location.clone(),
+        operating_hours: s.operating_hours.clone(),
+        price_range: s.price_range.clone(),
+        tags: s.tags.clone(),
+        reviews: s.reviews,
+        likes: s.likes,
+        rating: s.rating,
+        saved: s.saved,
+        is_open_now: s.is_open_now,
+        closes_in_minutes: s.closes_in_minutes
+    }
+}
+
+pub async fn list_canteens(
+    pg_pool: &PgPool,
+    _request: Recv<ListCanteensRequest>
+) -> Send<ListCanteensResponse> {
+
+    let canteens: Result<Vec<CanteenRow>, Error> = sqlx::query_as(
+        "
+        SELECT c.id, c.name, c.building, c.latitude, c.longitude, c.floors,
+        COUNT(s.id) AS stall_count
+        FROM kueater.canteen c
+        LEFT JOIN kueater.stall s ON s.canteen_id = c.id
+        GROUP BY c.id
+        ORDER BY c.name
+        "
+    ).fetch_all(pg_pool).await;
+
+    match canteens {
+        Ok(rows) => {
+            Ok(Response::new(ListCanteensResponse {
+                canteens: rows.iter().map(to_canteen).collect()
+            }))
+        }
+        Err(e) => {
+            println!("{}", e);
+            Err(Status::internal("Cannot list canteens"))
+        }
+    }
+}
+
+pub async fn canteen_stalls(
+    pg_pool: &PgPool,
+    request: Recv<CanteenStallsRequest>
+) -> Send<CanteenStallsResponse> {
+
+    let extensions = request.extensions().clone();
+    let data = request.into_inner();
+
+    let user_id = extensions.get::<super::UserContext>().unwrap().user_id.clone();
+
+    let user_id = match user_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("User id not a UUID"));
+        }
+    };
+
+    let canteen_id = match data.canteen_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("Canteen id not a UUID"));
+        }
+    };
+
+    let canteen: CanteenRow = match sqlx::query_as(
+        "
+        SELECT c.id, c.name, c.building, c.latitude, c.longitude, c.floors,
+        COUNT(s.id) AS stall_count
+        FROM kueater.canteen c
+        LEFT JOIN kueater.stall s ON s.canteen_id = c.id
+        WHERE c.id = $1
+        GROUP BY c.id
+        "
+    ).bind(canteen_id).fetch_optional(pg_pool).await {
+        Ok(Some(row)) => row,
+        Ok(None) => {
+            return Err(Status::not_found("Canteen not found"));
+        }
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+
+    let items_to_query: Vec<Uuid> = match sqlx::query_as::<_, (Uuid,)>(
+        "
+        SELECT id FROM kueater.stall WHERE canteen_id = $1
+        ORDER BY floor NULLS LAST, lock NULLS LAST, name
+        "
+    ).bind(canteen_id).fetch_all(pg_pool).await {
+        Ok(rows) => rows.iter().map(|(i,)|*i).collect(),
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+
+    let stall_conversions: Vec<Stall> = stream::iter(&items_to_query)
+        .then(|uuid| async move {
+            let stall: Stall = sqlx::query_as(
+                "SELECT * FROM kueater.get_stall_data_props($1, $2)"
+            )
+            .bind(uuid)
+            .bind(user_id)
+            .fetch_one(pg_pool).await.unwrap();
+            stall
+        }).collect().await;
+
+    Ok(Response::new(CanteenStallsResponse {
+        canteen: Some(to_canteen(&canteen)),
+        stalls: stall_conversions.iter().map(to_stall_props).collect()
+    }))
+}
+
+// Stalls sorted by distance of their canteen from the given coordinate.
+// Stalls without a canteen have no position and are left out.
+pub async fn nearby_stalls(
+    pg_pool: &PgPool,
+    request: Recv<NearbyStallsRequest>
+) -> Send<NearbyStallsResponse> {
+
+    let extensions = request.extensions().clone();
+    let data = request.into_inner();
+
+    let user_id = extensions.get::<super::UserContext>().unwrap().user_id.clone();
+
+    let user_id = match user_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("User id not a UUID"));
+        }
+    };
+
+    if !(-90.0..=90.0).contains(&data.latitude) || !(-180.0..=180.0).contains(&data.longitude) {
+        return Err(Status::invalid_argument("Coordinate out of range"));
+    }
+
+    let limit = match data.limit {
+        n if n <= 0 => 20,
+        n => n.min(100) as usize
+    };
+
+    let positions: Vec<StallPosition> = match sqlx::query_as(
+        "
+        SELECT s.id AS stall_id, c.id AS canteen_id, c.name AS canteen_name,
+        s.floor, c.latitude, c.longitude
+        FROM kueater.stall s
+        JOIN kueater.canteen c ON s.canteen_id = c.id
+        "
+    ).fetch_all(pg_pool).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+
+    let mut by_distance: Vec<(StallPosition, f64)> = positions.into_iter()
+        .map(|p| {
+            let d = haversine_meters(data.latitude, data.longitude, p.latitude, p.longitude);
+            (p, d)
+        }).collect();
+    by_distance.sort_by(|a, b| a.1.total_cmp(&b.1));
+    by_distance.truncate(limit);
+
+    let stall_conversions: Vec<Stall> = stream::iter(&by_distance)
+        .then(|(p, _)| async move {
+            let stall: Stall = sqlx::query_as(
+                "SELECT * FROM kueater.get_stall_data_props($1, $2)"
+            )
+            .bind(p.stall_id)
+            .bind(user_id)
+            .fetch_one(pg_pool).await.unwrap();
+            stall
+        }).collect().await;
+
+    Ok(Response::new(NearbyStallsResponse {
+        stalls: stall_conversions.iter().zip(&by_distance).map(|(s, (p, d))| NearbyStall {
+            stall: Some(to_stall_props(s)),
+            canteen_id: p.canteen_id.to_string(),
+            canteen_name: p.canteen_name.clone(),
+            floor: p.floor,
+            distance_meters: *d
+        }).collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Within half a percent of the published great-circle distance
+    fn assert_near(meters: f64, expected: f64) {
+        assert!((meters - expected).abs() <= expected * 0.005, "{} m, expected about {} m", meters, expected);
+    }
+
+    #[test]
+    fn known_city_pairs() {
+        // Paris to London
+        assert_near(haversine_meters(48.8566, 2.3522, 51.5074, -0.1278), 343_500.0);
+        // Bangkok to Chiang Mai
+        assert_near(haversine_meters(13.7563, 100.5018, 18.7883, 98.9853), 582_500.0);
+        // New York to London
+        assert_near(haversine_meters(40.7128, -74.0060, 51.5074, -0.1278), 5_570_000.0);
+    }
+
+    #[test]
+    fn same_point_is_zero() {
+        assert_eq!(haversine_meters(13.8476, 100.5696, 13.8476, 100.5696), 0.0);
+        assert_eq!(haversine_meters(0.0, 0.0, 0.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn distance_is_symmetric() {
+        let there = haversine_meters(13.8476, 100.5696, 13.8450, 100.5710);
+        let back = haversine_meters(13.8450, 100.5710, 13.8476, 100.5696);
+        assert!((there - back).abs() < 1e-6);
+    }
+
+    #[test]
+    fn antipodes_are_half_the_circumference() {
+        assert_near(haversine_meters(0.0, 0.0, 0.0, 180.0), std::f64::consts::PI * EARTH_RADIUS_METERS);
+    }
+}
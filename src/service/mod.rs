@@ -17,6 +17,9 @@ pub mod kueater {
         pub mod activity {
             tonic::include_proto!("kueater.data.activity");
         }
+        pub mod location {
+            tonic::include_proto!("kueater.data.location");
+        }
     }
     pub mod debug {
         tonic::include_proto!("kueater.debug");
@@ -31,12 +34,36 @@ pub struct UserContext {
     pub user_id: String,
 }
 
+// Read an optional tuning variable, falling back to default when unset or unparsable
+pub fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    match std::env::var(key) {
+        Ok(v) => v.parse::<T>().unwrap_or(default),
+        Err(_) => default
+    }
+}
+
+// Read a background job period in seconds. Tokio intervals panic on zero, so it is at least a second.
+pub fn env_period(key: &str, default: u64) -> std::time::Duration {
+    std::time::Duration::from_secs(env_or(key, default).max(1))
+}
+
 mod after;
 mod getters;
 mod home;
-mod search;
+pub mod search;
 mod review;
 mod saved;
 mod activity;
 mod profile;
-pub mod backend;
\ No newline at end of file
+mod location;
+mod similar;
+mod explain;
+mod allergen;
+mod hidden;
+pub mod backend;
+pub mod ranking;
+pub mod neighbors;
+pub mod experiments;
+pub mod score_generations;
+#[cfg(test)]
+mod test_db;
@@ -0,0 +1,143 @@
+use std::time::Duration;
+
+use sqlx::{PgExecutor, PgPool};
+
+use super::{env_or, env_period};
+
+// Settings for kueater.refresh_item_neighbors, see migration 0024 for the similarity.
+#[derive(Debug, Clone)]
+pub struct ItemNeighborsConfig {
+    pub top_k: i32,             // Neighbours kept per item
+    pub min_co_users: i32,      // Users two items must share to be neighbours
+    pub refresh_interval: Duration
+}
+
+impl ItemNeighborsConfig {
+    pub fn from_env() -> Self {
+        Self {
+            top_k: env_or("ITEM_NEIGHBORS_TOP_K", 20),
+            min_co_users: env_or("ITEM_NEIGHBORS_MIN_CO_USERS", 2),
+            refresh_interval: env_period("ITEM_NEIGHBORS_REFRESH_SECS", 900)
+        }
+    }
+}
+
+pub async fn refresh_item_neighbors<'e>(
+    executor: impl PgExecutor<'e>,
+    config: &ItemNeighborsConfig
+) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT kueater.refresh_item_neighbors($1, $2)")
+        .bind(config.top_k)
+        .bind(config.min_co_users)
+        .execute(executor).await?;
+    Ok(())
+}
+
+// Recompute kueater.item_neighbors forever, on every refresh interval.
+pub async fn run_item_neighbors_refresher(pg_pool: PgPool, config: ItemNeighborsConfig) {
+    let mut interval = tokio::time::interval(config.refresh_interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = refresh_item_neighbors(&pg_pool, &config).await {
+            println!("Cannot refresh item neighbours: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::types::Uuid;
+    use sqlx::PgConnection;
+
+    use super::*;
+    use super::super::test_db;
+
+    // Users 0 and 1 like items 0 and 1, user 2 items 0 and 2 and saves 3, user 3 likes 1 and 2:
+    //      users   item 0: 3, item 1: 3, item 2: 2, item 3: 1
+    //      shared  0-1: 2, 0-2: 1, 0-3: 1, 1-2: 1, 2-3: 1
+    async fn interactions(conn: &mut PgConnection) -> Vec<Uuid> {
+        let items = test_db::menu_items(conn, 4).await;
+        let mut users = vec![];
+        for _ in 0..4 {
+            users.push(test_db::user(conn).await);
+        }
+        let likes = [(0, 0), (0, 1), (1, 0), (1, 1), (2, 0), (2, 2), (3, 1), (3, 2)];
+        for (u, i) in likes {
+            sqlx::query("INSERT INTO kueater.liked_item (user_id, menu_id) VALUES ($1, $2)")
+                .bind(users[u]).bind(items[i])
+                .execute(&mut *conn).await.unwrap();
+        }
+        sqlx::query("INSERT INTO kueater.saved_item (user_id, menu_id) VALUES ($1, $2)")
+            .bind(users[2]).bind(items[3])
+            .execute(&mut *conn).await.unwrap();
+        items
+    }
+
+    async fn neighbors(conn: &mut PgConnection, items: &[Uuid], config: ItemNeighborsConfig) -> Vec<(usize, usize, f64, i32)> {
+        refresh_item_neighbors(&mut *conn, &config).await.unwrap();
+        let rows: Vec<(Uuid, Uuid, f64, i32)> = sqlx::query_as(
+            "
+            SELECT menu_id, neighbor_id, similarity, co_users FROM kueater.item_neighbors
+            WHERE menu_id = ANY ($1)
+            ORDER BY menu_id, similarity DESC, neighbor_id
+            "
+        ).bind(items).fetch_all(conn).await.unwrap();
+        let index = |id: Uuid| items.iter().position(|i| *i == id).unwrap();
+        rows.into_iter().map(|(m, n, s, c)| (index(m), index(n), s, c)).collect()
+    }
+
+    fn config(top_k: i32, min_co_users: i32) -> ItemNeighborsConfig {
+        ItemNeighborsConfig { top_k, min_co_users, refresh_interval: Duration::from_secs(1) }
+    }
+
+    fn assert_neighbors(actual: &[(usize, usize, f64, i32)], expected: &[(usize, usize, f64, i32)]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (a, e) in actual.iter().zip(expected) {
+            assert_eq!((a.0, a.1, a.3), (e.0, e.1, e.3), "{:?}", actual);
+            assert!((a.2 - e.2).abs() < 1e-9, "{:?}", actual);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn similarity_is_the_cosine_of_the_user_sets() {
+        let mut tx = test_db::begin().await;
+        let items = interactions(&mut tx).await;
+
+        let (two_thirds, sixth, third, half) = (2.0 / 3.0, 1.0 / 6.0_f64.sqrt(), 1.0 / 3.0_f64.sqrt(), 1.0 / 2.0_f64.sqrt());
+        assert_neighbors(&neighbors(&mut tx, &items, config(20, 1)).await, &[
+            (0, 1, two_thirds, 2), (0, 3, third, 1), (0, 2, sixth, 1),
+            (1, 0, two_thirds, 2), (1, 2, sixth, 1),
+            (2, 3, half, 1), (2, 0, sixth, 1), (2, 1, sixth, 1),
+            (3, 2, half, 1), (3, 0, third, 1)
+        ]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn pairs_below_min_co_users_are_dropped() {
+        let mut tx = test_db::begin().await;
+        let items = interactions(&mut tx).await;
+
+        assert_neighbors(&neighbors(&mut tx, &items, config(20, 2)).await, &[
+            (0, 1, 2.0 / 3.0, 2),
+            (1, 0, 2.0 / 3.0, 2)
+        ]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn top_k_keeps_the_closest_breaking_ties_by_id() {
+        let mut tx = test_db::begin().await;
+        let items = interactions(&mut tx).await;
+
+        // Items 0 and 1 are as close to item 2, the smaller id is kept
+        let (two_thirds, sixth, third, half) = (2.0 / 3.0, 1.0 / 6.0_f64.sqrt(), 1.0 / 3.0_f64.sqrt(), 1.0 / 2.0_f64.sqrt());
+        assert_neighbors(&neighbors(&mut tx, &items, config(2, 1)).await, &[
+            (0, 1, two_thirds, 2), (0, 3, third, 1),
+            (1, 0, two_thirds, 2), (1, 2, sixth, 1),
+            (2, 3, half, 1), (2, 0, sixth, 1),
+            (3, 2, half, 1), (3, 0, third, 1)
+        ]);
+    }
+}
@@ -111,4 +111,40 @@ pub async fn save_preferences(
             return Err(Status::internal("Update preferences failed"));
         }
     }
-}
\ No newline at end of file
+}
+
+pub async fn set_allergen_policy(
+    pg_pool: &PgPool,
+    request: Recv<SetAllergenPolicyRequest>
+) -> Send<Empty> {
+    let extensions = request.extensions().clone();
+    let data = request.into_inner();
+
+    let user_id = extensions.get::<super::UserContext>().unwrap().user_id.clone();
+
+    let user_id = match user_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("User id not a UUID"));
+        }
+    };
+
+    let policy = match data.policy() {
+        AllergenPolicy::Warn => "warn",
+        AllergenPolicy::Hide => "hide"
+    };
+
+    match sqlx::query(
+        "UPDATE kueater.userprofile SET allergen_policy = $2::kueater.allergen_policy WHERE id = $1"
+    )
+    .bind(user_id)
+    .bind(policy)
+    .execute(pg_pool)
+    .await {
+        Ok(_) => Ok(Response::new(Empty {})),
+        Err(e) => {
+            println!("{}", e);
+            Err(Status::internal("Update allergen policy failed"))
+        }
+    }
+}
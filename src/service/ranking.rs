@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use super::{env_or, env_period};
+
+// Weights for kueater.refresh_stall_rank, see migration 0011 for the formula.
+#[derive(Debug, Clone)]
+pub struct StallRankConfig {
+    pub like_weight: f64,
+    pub review_weight: f64,
+    pub rating_weight: f64,
+    pub prior_weight: f64,      // How many "average" reviews every stall starts with
+    pub refresh_interval: Duration
+}
+
+impl StallRankConfig {
+    pub fn from_env() -> Self {
+        Self {
+            like_weight: env_or("STALL_RANK_LIKE_WEIGHT", 0.4),
+            review_weight: env_or("STALL_RANK_REVIEW_WEIGHT", 0.3),
+            rating_weight: env_or("STALL_RANK_RATING_WEIGHT", 0.3),
+            // Stalls without reviews divide by the prior weight alone, keep it above zero
+            prior_weight: env_or("STALL_RANK_PRIOR_WEIGHT", 5.0_f64).max(f64::MIN_POSITIVE),
+            refresh_interval: env_period("STALL_RANK_REFRESH_SECS", 300)
+        }
+    }
+}
+
+pub async fn refresh_stall_rank(
+    pg_pool: &PgPool,
+    config: &StallRankConfig
+) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT kueater.refresh_stall_rank($1, $2, $3, $4)")
+        .bind(config.like_weight)
+        .bind(config.review_weight)
+        .bind(config.rating_weight)
+        .bind(config.prior_weight)
+        .execute(pg_pool).await?;
+    Ok(())
+}
+
+// Recompute kueater.stall_rank forever, on every refresh interval.
+pub async fn run_stall_rank_refresher(pg_pool: PgPool, config: StallRankConfig) {
+    let mut interval = tokio::time::interval(config.refresh_interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = refresh_stall_rank(&pg_pool, &config).await {
+            println!("Cannot refresh stall ranking: {}", e);
+        }
+    }
+}
@@ -41,7 +41,9 @@ struct Stall {
     reviews: i32,
     likes: i32,     // Aggregate from menu likes
     rating: f32,
-    saved: bool
+    saved: bool,
+    is_open_now: bool,
+    closes_in_minutes: Option<i32>
 }
 
 pub async fn saved_items(
@@ -76,6 +78,15 @@ pub async fn saved_items(
         }
     }
 
+    // Saved by the user themselves, so warned about but never hidden
+    let allergens = match super::allergen::AllergenGuard::load(pg_pool, &user_id, &items_to_query).await {
+        Ok(res) => res,
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+
     let conversions: Vec<MenuItem> = stream::iter(&items_to_query)
         .then(|uuid| async move {
             let item: MenuItem = sqlx::query_as(
@@ -87,6 +98,8 @@ pub async fn saved_items(
             return item
         }).collect().await;
 
+    let explanations = super::explain::explain_items(pg_pool, &user_id, &items_to_query).await;
+
     Ok(Response::new(
         SavedItemsResponse {
         menus: conversions.iter().map(|i| types::MenuCardProps {
@@ -106,7 +119,9 @@ pub async fn saved_items(
             reason: i.reason.clone(),
             liked: i.liked,
             disliked: i.disliked,
-            saved: i.saved
+            saved: i.saved,
+            explanation: explanations.get(&i.uuid).cloned(),
+            allergen_warning: allergens.warning(&i.uuid)
         }).collect()
     }
     ))
@@ -169,7 +184,9 @@ pub async fn saved_stalls(
                 reviews: s.reviews,
                 likes: s.likes,
                 rating: s.rating,
-                saved: s.saved
+                saved: s.saved,
+                is_open_now: s.is_open_now,
+                closes_in_minutes: s.closes_in_minutes
             }).collect()
         }
     ))
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use sqlx::{PgExecutor, PgPool};
+
+use super::{env_or, env_period};
+
+// Settings for kueater.prune_menuitem_score_generations, see migration 0027.
+#[derive(Debug, Clone)]
+pub struct ScorePruneConfig {
+    pub retention_secs: i32,    // Keep at least CURSOR_TTL_SECS, or listings may lose their pages
+    pub prune_interval: Duration
+}
+
+impl ScorePruneConfig {
+    pub fn from_env() -> Self {
+        Self {
+            retention_secs: env_or("SCORE_GENERATION_RETENTION_SECS", 86400).max(0),
+            prune_interval: env_period("SCORE_GENERATION_PRUNE_SECS", 3600)
+        }
+    }
+}
+
+pub async fn prune_score_generations<'e>(
+    executor: impl PgExecutor<'e>,
+    config: &ScorePruneConfig
+) -> Result<i64, sqlx::Error> {
+    let (pruned,): (i64,) = sqlx::query_as("SELECT kueater.prune_menuitem_score_generations($1)")
+        .bind(config.retention_secs)
+        .fetch_one(executor).await?;
+    Ok(pruned)
+}
+
+// Drop superseded scoring generations forever, on every prune interval.
+pub async fn run_score_generation_pruner(pg_pool: PgPool, config: ScorePruneConfig) {
+    let mut interval = tokio::time::interval(config.prune_interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = prune_score_generations(&pg_pool, &config).await {
+            println!("Cannot prune score generations: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::types::Uuid;
+    use sqlx::PgConnection;
+
+    use super::*;
+    use super::super::test_db;
+
+    async fn scores(conn: &mut PgConnection, user_id: Uuid, item: Uuid, generation: i64, hours_ago: i32, stale: bool) {
+        sqlx::query(
+            "
+            INSERT INTO kueater.menuitem_scores (user_id, menu_id, score, generation, stale, created_at)
+            VALUES ($1, $2, 0.5, $3, $4, LOCALTIMESTAMP - make_interval(hours => $5))
+            "
+        ).bind(user_id).bind(item).bind(generation).bind(stale).bind(hours_ago).execute(conn).await.unwrap();
+    }
+
+    async fn generations(conn: &mut PgConnection, user_id: Uuid) -> Vec<i64> {
+        let rows: Vec<(i64,)> = sqlx::query_as(
+            "SELECT DISTINCT generation FROM kueater.menuitem_scores WHERE user_id = $1 ORDER BY generation"
+        ).bind(user_id).fetch_all(conn).await.unwrap();
+        rows.into_iter().map(|(g,)| g).collect()
+    }
+
+    fn config() -> ScorePruneConfig {
+        ScorePruneConfig { retention_secs: 86400, prune_interval: Duration::from_secs(1) }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn only_generations_superseded_for_the_retention_are_pruned() {
+        let mut tx = test_db::begin().await;
+        let user_id = test_db::user(&mut tx).await;
+        let item = test_db::menu_items(&mut tx, 1).await[0];
+
+        scores(&mut tx, user_id, item, 1, 72, true).await;
+        scores(&mut tx, user_id, item, 2, 48, true).await;
+        // Listings of generation 2 may still be paging, generation 3 is only an hour old
+        scores(&mut tx, user_id, item, 3, 1, false).await;
+
+        assert_eq!(prune_score_generations(&mut *tx, &config()).await.unwrap(), 1);
+        assert_eq!(generations(&mut tx, user_id).await, vec![2, 3]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn staling_keeps_every_generation() {
+        let mut tx = test_db::begin().await;
+        let user_id = test_db::user(&mut tx).await;
+        let item = test_db::menu_items(&mut tx, 1).await[0];
+
+        scores(&mut tx, user_id, item, 1, 72, true).await;
+        scores(&mut tx, user_id, item, 2, 48, false).await;
+        for _ in 0..2 {
+            sqlx::query("SELECT kueater.stale_menuitem_scores_of($1)")
+                .bind(user_id).execute(&mut *tx).await.unwrap();
+        }
+        assert_eq!(generations(&mut tx, user_id).await, vec![1, 2]);
+
+        // The newest generation with rows is never pruned, however old
+        assert_eq!(prune_score_generations(&mut *tx, &config()).await.unwrap(), 1);
+        assert_eq!(generations(&mut tx, user_id).await, vec![2]);
+    }
+}
@@ -0,0 +1,80 @@
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+
+use super::super::{env_or, env_period};
+
+// Query embeddings from the agent, keyed by normalized query text.
+// Bounded by entry count, least recently used goes first; entries also expire after the TTL
+// so a changed embedding model is picked up without a restart.
+#[derive(Debug)]
+pub struct EmbeddingCache {
+    ttl: Duration,
+    entries: Mutex<LruCache<String, (String, Instant)>>,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+impl EmbeddingCache {
+    pub fn from_env() -> Self {
+        let capacity = NonZeroUsize::new(env_or("EMBEDDING_CACHE_SIZE", 1000))
+            .unwrap_or(NonZeroUsize::MIN);
+        Self {
+            ttl: Duration::from_secs(env_or("EMBEDDING_CACHE_TTL_SECS", 3600)),
+            entries: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        let found = match entries.get(key) {
+            Some((vectors, at)) if at.elapsed() < self.ttl => Some(vectors.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None
+        };
+        match found {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed)
+        };
+        found
+    }
+
+    pub fn insert(&self, key: String, vectors: String) {
+        self.entries.lock().unwrap().put(key, (vectors, Instant::now()));
+    }
+
+    // (hits, misses, entries)
+    pub fn stats(&self) -> (u64, u64, usize) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            self.entries.lock().unwrap().len()
+        )
+    }
+}
+
+// Print cache counters every interval, only when something was looked up since the last print
+pub async fn run_embedding_cache_reporter(cache: Arc<EmbeddingCache>) {
+    let mut interval = tokio::time::interval(env_period("EMBEDDING_CACHE_LOG_SECS", 300));
+    let mut last_lookups = 0;
+    loop {
+        interval.tick().await;
+        let (hits, misses, entries) = cache.stats();
+        if hits + misses == last_lookups {
+            continue;
+        }
+        last_lookups = hits + misses;
+        println!(
+            "Embedding cache: {} hits, {} misses ({:.1}% hit rate), {} entries",
+            hits, misses, hits as f64 * 100.0 / (hits + misses) as f64, entries
+        );
+    }
+}
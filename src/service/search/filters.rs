@@ -0,0 +1,162 @@
+use sqlx::postgres::{PgArguments, Postgres};
+use sqlx::query::QueryAs;
+use sqlx::types::Uuid;
+
+use super::super::kueater::data::search::SearchFilters;
+
+// Arguments of kueater.filter_menuitems, None for filters the client left empty.
+#[derive(Debug, Default)]
+pub struct MenuFilter {
+    price_min: Option<f64>,
+    price_max: Option<f64>,
+    cuisines: Option<Vec<String>>,
+    food_types: Option<Vec<String>>,
+    exclude_allergens: Option<Vec<String>>,
+    diets: Option<Vec<String>>,
+    stall_ids: Option<Vec<Uuid>>,
+    canteen_ids: Option<Vec<Uuid>>
+}
+
+// Values of the kueater.allergen and kueater.diet enums, migration 0001
+const ALLERGENS: &[&str] = &[
+    "Lactose", "Eggs", "Shellfish", "Fishes", "Seafood",
+    "Peanuts", "Gluten", "Sesame", "Nuts", "Soy", "Rice",
+    "Red Meat", "Corn", "Wheat", "Fructose", "Chocolate",
+    "Msg"
+];
+const DIETS: &[&str] = &[
+    "Halal", "Vegetarian", "Vegan", "Pescatarian",
+    "Pollotarian", "Low-Carb", "Keto", "Low-Fat", "High-Protein"
+];
+
+fn non_empty<T>(v: Vec<T>) -> Option<Vec<T>> {
+    if v.is_empty() { None } else { Some(v) }
+}
+
+fn parse_ids(ids: &[String], what: &str) -> Result<Option<Vec<Uuid>>, String> {
+    let mut parsed: Vec<Uuid> = vec![];
+    for id in ids {
+        match id.parse::<Uuid>() {
+            Ok(v) => parsed.push(v),
+            Err(_) => {
+                return Err(format!("{} id not a UUID", what));
+            }
+        }
+    }
+    Ok(non_empty(parsed))
+}
+
+// Names in any case, spelled as the enum. An unknown name is refused rather than dropped:
+// an allergen filter that silently matches nothing would show the client unsafe items.
+fn enum_names(names: &[String], known: &[&str], what: &str) -> Result<Option<Vec<String>>, String> {
+    let mut parsed: Vec<String> = vec![];
+    for name in names {
+        match known.iter().find(|k| k.eq_ignore_ascii_case(name.trim())) {
+            Some(k) => parsed.push(k.to_string()),
+            None => {
+                return Err(format!("Unknown {} {}", what, name));
+            }
+        }
+    }
+    Ok(non_empty(parsed))
+}
+
+impl MenuFilter {
+    // Err holds the message for an invalid argument status
+    pub fn from_request(filters: Option<SearchFilters>) -> Result<Self, String> {
+        let f = match filters {
+            Some(f) => f,
+            None => return Ok(Self::default())
+        };
+
+        if f.price_min.is_some_and(|v| v < 0.0) || f.price_max.is_some_and(|v| v < 0.0) {
+            return Err("Price filter is negative".to_string());
+        }
+        if let (Some(min), Some(max)) = (f.price_min, f.price_max) {
+            if min > max {
+                return Err("Minimum price is above maximum price".to_string());
+            }
+        }
+
+        Ok(Self {
+            price_min: f.price_min,
+            price_max: f.price_max,
+            stall_ids: parse_ids(&f.stall_ids, "Stall")?,
+            canteen_ids: parse_ids(&f.canteen_ids, "Canteen")?,
+            cuisines: non_empty(f.cuisines),
+            food_types: non_empty(f.food_types),
+            exclude_allergens: enum_names(&f.exclude_allergens, ALLERGENS, "allergen")?,
+            diets: enum_names(&f.diets, DIETS, "diet")?
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.price_min.is_none() && self.price_max.is_none()
+            && self.cuisines.is_none() && self.food_types.is_none()
+            && self.exclude_allergens.is_none() && self.diets.is_none()
+            && self.stall_ids.is_none() && self.canteen_ids.is_none()
+    }
+
+    // Binds the eight filter arguments, in kueater.filter_menuitems order.
+    // The query must have the filter placeholders right after the ones already bound.
+    pub fn bind<'q, O>(
+        &'q self,
+        query: QueryAs<'q, Postgres, O, PgArguments>
+    ) -> QueryAs<'q, Postgres, O, PgArguments> {
+        query
+            .bind(self.price_min)
+            .bind(self.price_max)
+            .bind(&self.cuisines)
+            .bind(&self.food_types)
+            .bind(&self.exclude_allergens)
+            .bind(&self.diets)
+            .bind(&self.stall_ids)
+            .bind(&self.canteen_ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(exclude_allergens: &[&str], diets: &[&str]) -> Option<SearchFilters> {
+        Some(SearchFilters {
+            exclude_allergens: exclude_allergens.iter().map(|s| s.to_string()).collect(),
+            diets: diets.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn names_in_any_case_are_spelled_as_the_enum() {
+        let filter = MenuFilter::from_request(filters(&["peanuts", "RED MEAT", " Eggs "], &["low-carb"])).unwrap();
+        assert_eq!(
+            filter.exclude_allergens,
+            Some(vec!["Peanuts".to_string(), "Red Meat".to_string(), "Eggs".to_string()])
+        );
+        assert_eq!(filter.diets, Some(vec!["Low-Carb".to_string()]));
+    }
+
+    #[test]
+    fn unknown_allergen_is_refused() {
+        assert_eq!(
+            MenuFilter::from_request(filters(&["Peanuts", "Peanut"], &[])).unwrap_err(),
+            "Unknown allergen Peanut"
+        );
+    }
+
+    #[test]
+    fn unknown_diet_is_refused() {
+        assert_eq!(
+            MenuFilter::from_request(filters(&[], &["Vegan", "Carnivore"])).unwrap_err(),
+            "Unknown diet Carnivore"
+        );
+    }
+
+    #[test]
+    fn empty_lists_do_not_filter() {
+        let filter = MenuFilter::from_request(filters(&[], &[])).unwrap();
+        assert_eq!(filter.exclude_allergens, None);
+        assert_eq!(filter.diets, None);
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::{HashMap, HashSet};
+
+use sqlx::types::Uuid;
+
+use super::super::env_or;
+use super::super::experiments::Assignment;
+
+// Tunables for merging ranked result lists, see reciprocal_rank_fusion.
+#[derive(Debug, Clone)]
+pub struct FusionWeights {
+    pub k: f64,         // Larger k flattens the advantage of the top ranks
+    pub text: f64,
+    pub vector: f64,
+    pub ingredient: f64,    // Dishes found through a matching ingredient
+    pub stall_frequency: f64,
+    pub stall_vector: f64,
+    pub ingredient_max_distance: f64    // Cosine distance past which an ingredient is unrelated
+}
+
+impl FusionWeights {
+    pub fn from_env() -> Self {
+        Self {
+            k: env_or("SEARCH_RRF_K", 60.0),
+            text: env_or("SEARCH_TEXT_WEIGHT", 1.0),
+            vector: env_or("SEARCH_VECTOR_WEIGHT", 1.0),
+            ingredient: env_or("SEARCH_INGREDIENT_WEIGHT", 0.5),
+            stall_frequency: env_or("SEARCH_STALL_FREQUENCY_WEIGHT", 1.0),
+            stall_vector: env_or("SEARCH_STALL_VECTOR_WEIGHT", 1.0),
+            ingredient_max_distance: env_or("SEARCH_INGREDIENT_MAX_DISTANCE", 0.5)
+        }
+    }
+
+    // Weights overridden by the user's search experiment variant, if any
+    pub fn with_variant(&self, assignment: &Assignment) -> Self {
+        Self {
+            k: assignment.param("rrf_k", self.k),
+            text: assignment.param("text_weight", self.text),
+            vector: assignment.param("vector_weight", self.vector),
+            ingredient: assignment.param("ingredient_weight", self.ingredient),
+            stall_frequency: assignment.param("stall_frequency_weight", self.stall_frequency),
+            stall_vector: assignment.param("stall_vector_weight", self.stall_vector),
+            ingredient_max_distance: assignment.param("ingredient_max_distance", self.ingredient_max_distance)
+        }
+    }
+}
+
+// Reciprocal rank fusion over several ranked lists, each with its own weight:
+//      relevance(d) = sum of weight / (k + rank of d in list), ranks starting at 1
+// An id repeated within one list only counts at its best rank.
+// Returns every id once, most relevant first; ties keep the order ids were first seen.
+pub fn reciprocal_rank_fusion(lists: &[(&[Uuid], f64)], k: f64) -> Vec<(Uuid, f64)> {
+    let mut scores: HashMap<Uuid, (usize, f64)> = HashMap::new();
+
+    for (list, weight) in lists {
+        let mut rank = 0;
+        let mut seen: HashSet<Uuid> = HashSet::new();
+        for id in list.iter() {
+            if !seen.insert(*id) {
+                continue;
+            }
+            rank += 1;
+            let first_seen = scores.len();
+            let entry = scores.entry(*id).or_insert((first_seen, 0.0));
+            entry.1 += weight / (k + rank as f64);
+        }
+    }
+
+    let mut fused: Vec<(Uuid, usize, f64)> = scores.into_iter()
+        .map(|(id, (first_seen, score))| (id, first_seen, score))
+        .collect();
+    fused.sort_by(|a, b| b.2.total_cmp(&a.2).then(a.1.cmp(&b.1)));
+    fused.into_iter().map(|(id, _, score)| (id, score)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(n: u128) -> Vec<Uuid> {
+        (1..=n).map(Uuid::from_u128).collect()
+    }
+
+    fn score_of(fused: &[(Uuid, f64)], id: Uuid) -> f64 {
+        fused.iter().find(|(i, _)| *i == id).unwrap().1
+    }
+
+    #[test]
+    fn item_in_one_list() {
+        let id = ids(3);
+        let text = [id[0], id[1]];
+        let vector = [id[2]];
+        let fused = reciprocal_rank_fusion(&[(&text, 1.0), (&vector, 1.0)], 60.0);
+
+        assert_eq!(fused.len(), 3);
+        assert!((score_of(&fused, id[1]) - 1.0 / 62.0).abs() < 1e-12);
+        assert!((score_of(&fused, id[2]) - 1.0 / 61.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn ranks_add_up_across_lists() {
+        let id = ids(4);
+        let text = [id[0], id[1], id[2]];
+        let vector = [id[3], id[2]];
+        let fused = reciprocal_rank_fusion(&[(&text, 1.0), (&vector, 2.0)], 60.0);
+
+        assert!((score_of(&fused, id[2]) - (1.0 / 63.0 + 2.0 / 62.0)).abs() < 1e-12);
+        assert_eq!(fused[0].0, id[2]);
+    }
+
+    #[test]
+    fn repeated_id_counts_at_best_rank() {
+        let id = ids(2);
+        let text = [id[0], id[1], id[0]];
+        let fused = reciprocal_rank_fusion(&[(&text, 1.0)], 60.0);
+
+        assert!((score_of(&fused, id[0]) - 1.0 / 61.0).abs() < 1e-12);
+        assert!((score_of(&fused, id[1]) - 1.0 / 62.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn ties_keep_first_seen_order() {
+        let id = ids(4);
+        let text = [id[1], id[3]];
+        let vector = [id[0], id[2]];
+        let fused = reciprocal_rank_fusion(&[(&text, 1.0), (&vector, 1.0)], 60.0);
+
+        let order: Vec<Uuid> = fused.iter().map(|(i, _)| *i).collect();
+        assert_eq!(order, vec![id[1], id[0], id[3], id[2]]);
+    }
+
+    #[test]
+    fn weights_change_the_order() {
+        let id = ids(2);
+        let text = [id[0]];
+        let vector = [id[1]];
+
+        let fused = reciprocal_rank_fusion(&[(&text, 1.0), (&vector, 2.0)], 60.0);
+        assert_eq!(fused[0].0, id[1]);
+
+        let fused = reciprocal_rank_fusion(&[(&text, 2.0), (&vector, 1.0)], 60.0);
+        assert_eq!(fused[0].0, id[0]);
+    }
+
+    #[test]
+    fn k_changes_the_order() {
+        // x is first in one list, y third in both: 1 / (k + 1) against 2 / (k + 3)
+        let id = ids(5);
+        let (x, y) = (id[0], id[4]);
+        let text = [x, id[1], y];
+        let vector = [id[2], id[3], y];
+
+        let fused = reciprocal_rank_fusion(&[(&text, 1.0), (&vector, 1.0)], 0.0);
+        assert_eq!(fused[0].0, x);
+
+        let fused = reciprocal_rank_fusion(&[(&text, 1.0), (&vector, 1.0)], 60.0);
+        assert_eq!(fused[0].0, y);
+    }
+}
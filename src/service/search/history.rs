@@ -0,0 +1,298 @@
+use sqlx::types::Uuid;
+use sqlx::{Error, PgPool};
+use tonic::{Response, Status};
+use unicode_normalization::UnicodeNormalization;
+
+use super::super::backend::{Send, Recv};
+use super::super::env_or;
+use super::super::kueater::{Empty, data::search::*};
+
+const DEFAULT_RECENT_LIMIT: i32 = 10;
+const MAX_LIMIT: i32 = 100;
+
+#[derive(Debug, sqlx::FromRow)]
+struct RecentRow {
+    query: String,
+    searched_at: String
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct TrendingRow {
+    query: String,
+    searches: i64
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ZeroResultRow {
+    query: String,
+    searches: i64,
+    users: i64,
+    last_searched: String
+}
+
+// "  Pad   KRAPRAO " and "pad kraprao" are the same search,
+// as are full-width and composed / decomposed spellings (NFKC)
+pub fn normalize_query(query: &str) -> String {
+    query.nfkc().collect::<String>()
+        .split_whitespace()
+        .map(|w| w.to_lowercase())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn clamp_limit(requested: i32, default: i32) -> i32 {
+    match requested {
+        n if n <= 0 => default,
+        n => n.min(MAX_LIMIT)
+    }
+}
+
+// Keep a search unless the user opted out. History is best effort, failures never fail the search.
+// Filtered searches are kept apart from the zero-result report, their results may exist.
+pub async fn record(pg_pool: &PgPool, user_id: Uuid, query: &str, result_count: usize, filtered: bool) {
+    let result = sqlx::query(
+        "
+        INSERT INTO kueater.search_history (user_id, query, normalized, result_count, filtered)
+        SELECT id, $2, $3, $4, $5 FROM kueater.userprofile
+        WHERE id = $1 AND record_search_history
+        "
+    )
+    .bind(user_id)
+    .bind(query.trim())
+    .bind(normalize_query(query))
+    .bind(result_count as i32)
+    .bind(filtered)
+    .execute(pg_pool).await;
+
+    if let Err(e) = result {
+        println!("Cannot record search history: {}", e);
+    }
+}
+
+pub async fn recent_searches(
+    pg_pool: &PgPool,
+    request: Recv<RecentSearchesRequest>
+) -> Send<RecentSearchesResponse> {
+
+    let extensions = request.extensions().clone();
+
+    let user_id = extensions.get::<super::super::UserContext>().unwrap().user_id.clone();
+
+    let user_id = match user_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("User id not a UUID"));
+        }
+    };
+
+    let data = request.into_inner();
+
+    let enabled: Result<(bool,), Error> = sqlx::query_as(
+        "SELECT record_search_history FROM kueater.userprofile WHERE id = $1"
+    ).bind(user_id).fetch_one(pg_pool).await;
+
+    let enabled = match enabled {
+        Ok((v,)) => v,
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+
+    // Latest search of each distinct query
+    let recent: Result<Vec<RecentRow>, Error> = sqlx::query_as(
+        "
+        SELECT query, searched_at::TEXT AS searched_at FROM (
+            SELECT DISTINCT ON (normalized) query, created_at AS searched_at
+            FROM kueater.search_history
+            WHERE user_id = $1
+            ORDER BY normalized, created_at DESC
+        ) latest
+        ORDER BY latest.searched_at DESC
+        LIMIT $2
+        "
+    )
+    .bind(user_id)
+    .bind(clamp_limit(data.limit, DEFAULT_RECENT_LIMIT))
+    .fetch_all(pg_pool).await;
+
+    match recent {
+        Ok(rows) => {
+            Ok(Response::new(RecentSearchesResponse {
+                searches: rows.into_iter().map(|r| RecentSearch {
+                    query: r.query,
+                    searched_at: r.searched_at
+                }).collect(),
+                history_enabled: enabled
+            }))
+        }
+        Err(e) => {
+            println!("{}", e);
+            Err(Status::internal("Database failure"))
+        }
+    }
+}
+
+pub async fn clear_search_history(
+    pg_pool: &PgPool,
+    request: Recv<Empty>
+) -> Send<Empty> {
+
+    let extensions = request.extensions().clone();
+
+    let user_id = extensions.get::<super::super::UserContext>().unwrap().user_id.clone();
+
+    let user_id = match user_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("User id not a UUID"));
+        }
+    };
+
+    match sqlx::query("DELETE FROM kueater.search_history WHERE user_id = $1")
+        .bind(user_id)
+        .execute(pg_pool).await {
+        Ok(_) => Ok(Response::new(Empty {})),
+        Err(e) => {
+            println!("{}", e);
+            Err(Status::internal("Database failure"))
+        }
+    }
+}
+
+// Opting out also forgets what was recorded so far
+pub async fn set_search_history_enabled(
+    pg_pool: &PgPool,
+    request: Recv<SetSearchHistoryEnabledRequest>
+) -> Send<Empty> {
+
+    let extensions = request.extensions().clone();
+
+    let user_id = extensions.get::<super::super::UserContext>().unwrap().user_id.clone();
+
+    let user_id = match user_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("User id not a UUID"));
+        }
+    };
+
+    let data = request.into_inner();
+
+    let result: Result<(), Error> = async {
+        let mut tx = pg_pool.begin().await?;
+        sqlx::query("UPDATE kueater.userprofile SET record_search_history = $2 WHERE id = $1")
+            .bind(user_id)
+            .bind(data.enabled)
+            .execute(&mut *tx).await?;
+        if !data.enabled {
+            sqlx::query("DELETE FROM kueater.search_history WHERE user_id = $1")
+                .bind(user_id)
+                .execute(&mut *tx).await?;
+        }
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => Ok(Response::new(Empty {})),
+        Err(e) => {
+            println!("{}", e);
+            Err(Status::internal("Database failure"))
+        }
+    }
+}
+
+pub async fn trending_searches(
+    pg_pool: &PgPool,
+    request: Recv<TrendingSearchesRequest>
+) -> Send<TrendingSearchesResponse> {
+
+    let data = request.into_inner();
+
+    let trending: Result<Vec<TrendingRow>, Error> = sqlx::query_as(
+        "SELECT query, searches FROM kueater.trending_searches($1, $2, $3)"
+    )
+    .bind(env_or("TRENDING_SEARCH_WINDOW_HOURS", 24))
+    .bind(clamp_limit(data.limit, 10))
+    .bind(env_or("TRENDING_SEARCH_MIN_USERS", 2))
+    .fetch_all(pg_pool).await;
+
+    match trending {
+        Ok(rows) => {
+            Ok(Response::new(TrendingSearchesResponse {
+                searches: rows.into_iter().map(|r| TrendingSearch {
+                    query: r.query,
+                    searches: r.searches as i32
+                }).collect()
+            }))
+        }
+        Err(e) => {
+            println!("{}", e);
+            Err(Status::internal("Database failure"))
+        }
+    }
+}
+
+// Admins only: what people look for and the catalog does not have
+pub async fn zero_result_searches(
+    pg_pool: &PgPool,
+    request: Recv<ZeroResultSearchesRequest>
+) -> Send<ZeroResultSearchesResponse> {
+
+    let extensions = request.extensions().clone();
+
+    let user_id = extensions.get::<super::super::UserContext>().unwrap().user_id.clone();
+
+    let user_id = match user_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("User id not a UUID"));
+        }
+    };
+
+    let data = request.into_inner();
+
+    let is_admin: Result<(bool,), Error> = sqlx::query_as(
+        "SELECT EXISTS (SELECT 1 FROM kueater.admin_user WHERE user_id = $1)"
+    ).bind(user_id).fetch_one(pg_pool).await;
+
+    match is_admin {
+        Ok((true,)) => {}
+        Ok((false,)) => {
+            return Err(Status::permission_denied("Admins only"));
+        }
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    }
+
+    let window_hours = match data.window_hours {
+        n if n <= 0 => 168,
+        n => n
+    };
+
+    let queries: Result<Vec<ZeroResultRow>, Error> = sqlx::query_as(
+        "SELECT * FROM kueater.zero_result_searches($1, $2)"
+    )
+    .bind(window_hours)
+    .bind(clamp_limit(data.limit, 50))
+    .fetch_all(pg_pool).await;
+
+    match queries {
+        Ok(rows) => {
+            Ok(Response::new(ZeroResultSearchesResponse {
+                searches: rows.into_iter().map(|r| ZeroResultSearch {
+                    query: r.query,
+                    searches: r.searches as i32,
+                    users: r.users as i32,
+                    last_searched: r.last_searched
+                }).collect()
+            }))
+        }
+        Err(e) => {
+            println!("{}", e);
+            Err(Status::internal("Database failure"))
+        }
+    }
+}
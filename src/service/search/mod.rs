@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::{AgentCommand, Command, service::backend::AgentCommandSender};
 
@@ -10,9 +10,22 @@ use tokio::sync::oneshot;
 use tonic::{Response, Status};
 
 use super::backend::{Send, Recv};
+use super::experiments::{self, Experiments};
 use super::kueater::data::types;
 use super::kueater::{Empty, data::search::*};
 
+use embedding_cache::EmbeddingCache;
+use filters::MenuFilter;
+use fusion::FusionWeights;
+use session::{SearchSession, SearchSessions};
+
+pub mod embedding_cache;
+mod filters;
+pub mod fusion;
+pub mod history;
+pub mod session;
+pub mod suggest;
+
 #[derive(Debug, Deserialize, sqlx::FromRow)]
 struct MenuItem {
     uuid: String,
@@ -44,12 +57,18 @@ struct Stall {
     reviews: i32,
     likes: i32,     // Aggregate from menu likes
     rating: f32,
-    saved: bool
+    saved: bool,
+    is_open_now: bool,
+    closes_in_minutes: Option<i32>
 }
 
 pub async fn search(
     pg_pool: &PgPool,
     sender: &AgentCommandSender,
+    fusion_weights: &FusionWeights,
+    sessions: &SearchSessions,
+    embedding_cache: &EmbeddingCache,
+    experiments: &Experiments,
     request: Recv<SearchRequest>
 ) -> Send<SearchResponse> {
 
@@ -66,108 +85,85 @@ pub async fn search(
 
     let data = request.into_inner();
 
-    if data.query.is_empty() { return Err(Status::invalid_argument("Search query is empty")) }
-
-    // --- Text Search ---
-
-    let mut results: Vec<Uuid> = vec![];
-    let mut stalls: Vec<Uuid> = vec![];
+    let page_size = session::page_size(data.page_size);
 
-    let query = format!(
-        "
-        SELECT
-        id,
-        stall_id
-        FROM
-        kueater.menuitem mi
-        JOIN kueater.stall_menu sm ON mi.id = sm.menu_id
-        WHERE name ILIKE '%{}%'
-        LIMIT 200
-        ", data.query
-    );
-
-    match sqlx::query_as::<_, (Uuid,Uuid)>(&query).fetch_all(pg_pool).await {
-        Err(e) => {
-            println!("{}", e);
-            return Err(Status::internal("Database failure"))
+    // Later pages read the ranking cached by the first one
+    let (session_id, search_session, offset) = match data.cursor.as_deref() {
+        Some(cursor) if !cursor.is_empty() => {
+            let (session_id, offset) = match session::decode_cursor(cursor) {
+                Some(res) => res,
+                None => {
+                    return Err(Status::invalid_argument("Malformed search cursor"));
+                }
+            };
+            match sessions.get(&session_id) {
+                Some(s) if s.user_id == user_id => (session_id, s, offset),
+                _ => {
+                    return Err(Status::failed_precondition("Search session expired"));
+                }
+            }
         }
-        Ok(rows) => {
-            let mut search_results = rows.iter().map(|(i,_)|*i).collect();
-            let mut search_stalls = rows.iter().map(|(_,i)|*i).collect();
-            results.append(&mut search_results);
-            stalls.append(&mut search_stalls);
+        _ => {
+            let assignment = experiments.assign(experiments::SURFACE_SEARCH, &user_id);
+            experiments::log_exposure(pg_pool, &user_id, &assignment).await;
+            let fusion_weights = fusion_weights.with_variant(&assignment);
+            let (s, filtered) = rank(pg_pool, sender, &fusion_weights, embedding_cache, user_id, &data).await?;
+            history::record(pg_pool, user_id, &data.query, s.hits.len(), filtered).await;
+            (sessions.insert(s.clone()), s, 0)
         }
     };
 
-    // --- Vector Search ---
-
-    let (tx, rx) = oneshot::channel::<String>();
-
-    sender.send(AgentCommand { 
-        msg: Command::Search { query: data.query.clone() },
-        tx: Some(tx)
-    }).await.unwrap();
-
-    let mut vector_search_flag = true;
-    let vectors = rx.await.map_err(|_| { vector_search_flag = false });
+    let page: Vec<(Uuid, f64)> = search_session.hits.iter()
+        .skip(offset)
+        .take(page_size)
+        .copied()
+        .collect();
 
-    if vector_search_flag {
-        let query = format!(
-            "
-            SELECT
-            object_id,
-            stall_id
-            FROM
-            kueater.embeddings
-            JOIN kueater.menuitem mi ON object_id = mi.id
-            JOIN kueater.stall_menu sm ON mi.id = sm.menu_id
-            WHERE object_type = 'menuitem'
-            ORDER BY (1 - (embedding <=> '{}')) DESC
-            LIMIT 200
-            ", vectors.unwrap()
-        );
-        match sqlx::query_as::<_, (Uuid,Uuid)>(&query).fetch_all(pg_pool).await {
-            Err(e) => {
-                println!("{}", e);
-                return Err(Status::internal("Database failure"))
-            }
-            Ok(rows) => {
-                let mut vector_results = rows.iter().map(|(i,_)|*i).collect();
-                let mut vector_stalls = rows.iter().map(|(_,i)|*i).collect();
-                results.append(&mut vector_results);
-                stalls.append(&mut vector_stalls);
-            }
-        };
-    }
-
-    results.dedup();
-
-    if results.len() <= 0 {
-        return Ok(Response::new(SearchResponse { menus: vec![], stalls: vec![] }));
-    }
+    let next_cursor = match offset + page.len() {
+        next if next < search_session.hits.len() => Some(session::encode_cursor(&session_id, next)),
+        _ => None
+    };
 
-    let conversions: Vec<MenuItem> = stream::iter(&results)
-        .then(|uuid| async move {
+    let conversions: Vec<MenuItem> = stream::iter(&page)
+        .then(|(uuid, _)| async move {
             let item: MenuItem = sqlx::query_as(
                 "SELECT * FROM kueater.get_menu_card_props($1, $2)"
             )
-            .bind(&uuid)
-            .bind(&user_id)
+            .bind(uuid)
+            .bind(user_id)
             .fetch_one(pg_pool).await.unwrap();
-            return item
+            item
         }).collect().await;
-    
-    let stall_conversions: Vec<Stall> = stream::iter(&stalls_by_frequency(&stalls))
+
+    // Stalls come with the first page only
+    let stalls_to_query: &[Uuid] = if offset == 0 { &search_session.stalls } else { &[] };
+
+    let stall_conversions: Vec<Stall> = stream::iter(stalls_to_query)
         .then(|uuid| async move {
             let stall: Stall = sqlx::query_as(
                 "SELECT * FROM kueater.get_stall_data_props($1, $2)"
             )
-            .bind(&uuid)
-            .bind(&user_id)
+            .bind(uuid)
+            .bind(user_id)
             .fetch_one(pg_pool).await.unwrap();
-            return stall
+            stall
         }).collect().await;
 
+    let hits: Vec<SearchHit> = conversions.iter().zip(&page).map(|(i, (_, relevance))| SearchHit {
+        uuid: i.uuid.clone(),
+        relevance: *relevance as f32
+    }).collect();
+
+    let page_ids: Vec<Uuid> = page.iter().map(|(i,_)|*i).collect();
+    let explanations = super::explain::explain_items(pg_pool, &user_id, &page_ids).await;
+    let allergens = match super::allergen::AllergenGuard::load(pg_pool, &user_id, &page_ids).await {
+        Ok(res) => res,
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+
         Ok(Response::new(
             SearchResponse {
                 menus: conversions.iter().map(|i| types::MenuCardProps {
@@ -180,14 +176,13 @@ pub async fn search(
                     stall_name: i.stall_name.clone(),
                     stall_lock: i.stall_lock.clone(),
                     image_url: i.image_url.clone(),
-                    score: match i.score {
-                        Some(v) => Some(v as f32),
-                        None => None
-                    },
+                    score: i.score.map(|v| v as f32),
                     reason: i.reason.clone(),
                     liked: i.liked,
                     disliked: i.disliked,
-                    saved: i.saved
+                    saved: i.saved,
+                    explanation: explanations.get(&i.uuid).cloned(),
+                    allergen_warning: allergens.warning(&i.uuid)
                 }).collect(),
                 stalls: stall_conversions.iter().map(|s| types::StallDataTypeProps {
                     uuid: s.uuid.clone(),
@@ -201,12 +196,245 @@ pub async fn search(
                     reviews: s.reviews,
                     likes: s.likes,
                     rating: s.rating,
-                    saved: s.saved
-                }).collect()
+                    saved: s.saved,
+                    is_open_now: s.is_open_now,
+                    closes_in_minutes: s.closes_in_minutes
+                }).collect(),
+                hits,
+                next_cursor
                     }
                 ))
 }
 
+// Runs text and vector search and fuses them into one ranking for a new session
+async fn rank(
+    pg_pool: &PgPool,
+    sender: &AgentCommandSender,
+    fusion_weights: &FusionWeights,
+    embedding_cache: &EmbeddingCache,
+    user_id: Uuid,
+    data: &SearchRequest
+) -> Result<(SearchSession, bool), Status> {
+
+    if data.query.is_empty() { return Err(Status::invalid_argument("Search query is empty")) }
+
+    let filter = MenuFilter::from_request(data.filters.clone()).map_err(Status::invalid_argument)?;
+
+    // --- Text Search ---
+    // Trigram and full-text matches on dish, stall and ingredient names, see migration 0015
+
+    // (menu, stall) pairs from each path, best match first
+    let mut text_hits: Vec<(Uuid, Uuid)> = vec![];
+    let mut vector_hits: Vec<(Uuid, Uuid)> = vec![];
+    let mut ingredient_hits: Vec<(Uuid, Uuid)> = vec![];
+    let mut stall_vector_hits: Vec<Uuid> = vec![];
+
+    match filter.bind(sqlx::query_as::<_, (Uuid,Uuid)>(
+        "
+        SELECT
+        f.menu_id,
+        f.stall_id
+        FROM
+        kueater.text_search_menuitems($1) t
+        JOIN kueater.filter_menuitems($2, $3, $4, $5, $6, $7, $8, $9) f ON f.menu_id = t.menu_id
+        ORDER BY t.score DESC, t.menu_id
+        LIMIT 200
+        "
+    ).bind(&data.query)).fetch_all(pg_pool).await {
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"))
+        }
+        Ok(rows) => {
+            text_hits = rows;
+        }
+    };
+
+    // --- Vector Search ---
+
+    // Repeated queries reuse the embedding instead of asking the agent again.
+    // The agent embeds the normalized text too, so spellings sharing an entry share its vector.
+    let cache_key = history::normalize_query(&data.query);
+
+    let vectors = match embedding_cache.get(&cache_key) {
+        Some(v) => Some(v),
+        None => {
+            let (tx, rx) = oneshot::channel::<String>();
+
+            sender.send(AgentCommand { 
+                msg: Command::Search { query: cache_key.clone() },
+                tx: Some(tx)
+            }).await.unwrap();
+
+            match rx.await {
+                Ok(v) => {
+                    embedding_cache.insert(cache_key, v.clone());
+                    Some(v)
+                }
+                Err(_) => None
+            }
+        }
+    };
+
+    if let Some(vectors) = vectors {
+        match filter.bind(sqlx::query_as::<_, (Uuid,Uuid)>(
+            "
+            SELECT
+            f.menu_id,
+            f.stall_id
+            FROM
+            kueater.embeddings e
+            JOIN kueater.filter_menuitems($2, $3, $4, $5, $6, $7, $8, $9) f ON e.object_id = f.menu_id
+            WHERE e.object_type = 'menuitem'
+            ORDER BY e.embedding <=> $1::vector
+            LIMIT 200
+            "
+        ).bind(&vectors)).fetch_all(pg_pool).await {
+            Err(e) => {
+                println!("{}", e);
+                return Err(Status::internal("Database failure"))
+            }
+            Ok(rows) => {
+                vector_hits = rows;
+            }
+        };
+
+        // Dishes containing the ingredients closest to the query, "something with basil"
+        match filter.bind(sqlx::query_as::<_, (Uuid,Uuid)>(
+            "
+            WITH ing AS (
+                SELECT e.object_id AS ingredient_id, e.embedding <=> $1::vector AS distance
+                FROM kueater.embeddings e
+                WHERE e.object_type = 'ingredient'
+                ORDER BY e.embedding <=> $1::vector
+                LIMIT 5
+            )
+            SELECT
+            f.menu_id,
+            f.stall_id
+            FROM
+            ing
+            JOIN kueater.menu_ingredient mi ON mi.ingredient_id = ing.ingredient_id
+            JOIN kueater.filter_menuitems($2, $3, $4, $5, $6, $7, $8, $9) f ON f.menu_id = mi.menu_id
+            WHERE ing.distance <= $10
+            GROUP BY f.menu_id, f.stall_id
+            ORDER BY MIN(ing.distance), f.menu_id
+            LIMIT 200
+            "
+        ).bind(&vectors)).bind(fusion_weights.ingredient_max_distance).fetch_all(pg_pool).await {
+            Err(e) => {
+                println!("{}", e);
+                return Err(Status::internal("Database failure"))
+            }
+            Ok(rows) => {
+                ingredient_hits = rows;
+            }
+        };
+
+        // Stalls whose own embedding is close, limited to stalls with a dish passing the filters
+        match filter.bind(sqlx::query_as::<_, (Uuid,)>(
+            "
+            SELECT
+            e.object_id
+            FROM
+            kueater.embeddings e
+            WHERE e.object_type = 'stall'
+            AND e.object_id IN (
+                SELECT f.stall_id FROM kueater.filter_menuitems($2, $3, $4, $5, $6, $7, $8, $9) f
+            )
+            ORDER BY e.embedding <=> $1::vector
+            LIMIT 20
+            "
+        ).bind(vectors)).fetch_all(pg_pool).await {
+            Err(e) => {
+                println!("{}", e);
+                return Err(Status::internal("Database failure"))
+            }
+            Ok(rows) => {
+                stall_vector_hits = rows.into_iter().map(|(i,)|i).collect();
+            }
+        };
+    }
+
+    // --- Fusion ---
+
+    let text_ids: Vec<Uuid> = text_hits.iter().map(|(i,_)|*i).collect();
+    let vector_ids: Vec<Uuid> = vector_hits.iter().map(|(i,_)|*i).collect();
+    let ingredient_ids: Vec<Uuid> = ingredient_hits.iter().map(|(i,_)|*i).collect();
+
+    let mut fused = fusion::reciprocal_rank_fusion(
+        &[
+            (&text_ids, fusion_weights.text),
+            (&vector_ids, fusion_weights.vector),
+            (&ingredient_ids, fusion_weights.ingredient)
+        ],
+        fusion_weights.k
+    );
+
+    let menu_stalls: Vec<(Uuid, Uuid)> = text_hits.into_iter()
+        .chain(vector_hits)
+        .chain(ingredient_hits)
+        .collect();
+
+    // How often a stall shows up among the dishes, merged with matches on the stall itself
+    let stalls_by_dishes = stalls_by_frequency(&menu_stalls.iter().map(|(_,i)|*i).collect());
+    let mut stalls: Vec<Uuid> = fusion::reciprocal_rank_fusion(
+        &[(&stalls_by_dishes, fusion_weights.stall_frequency), (&stall_vector_hits, fusion_weights.stall_vector)],
+        fusion_weights.k
+    ).into_iter().map(|(i,_)|i).collect();
+
+    // --- Opening hours ---
+
+    let open_stalls: HashSet<Uuid> = match sqlx::query_as::<_, (Uuid,)>(
+        "
+        SELECT s.id
+        FROM unnest($1::UUID[]) AS s(id)
+        WHERE (SELECT is_open FROM kueater.stall_open_status(s.id))
+        "
+    ).bind(&stalls).fetch_all(pg_pool).await {
+        Ok(rows) => rows.into_iter().map(|(i,)|i).collect(),
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"))
+        }
+    };
+
+    // Open stalls first, keeping the frequency order within each group
+    stalls.sort_by_key(|s| !open_stalls.contains(s));
+
+    // Whether the client's filters, opening hours or the user's allergens may have taken
+    // dishes away, so an empty result is not a gap in the catalogue
+    let mut filtered = !filter.is_empty();
+
+    if data.open_only {
+        let matched = fused.len();
+        stalls.retain(|s| open_stalls.contains(s));
+        let open_menus: HashSet<Uuid> = menu_stalls.iter()
+            .filter(|(_, s)| open_stalls.contains(s))
+            .map(|(m, _)| *m)
+            .collect();
+        fused.retain(|(m, _)| open_menus.contains(m));
+        filtered |= fused.len() < matched;
+    }
+
+    // --- Allergens ---
+
+    // Hidden items are dropped from the session so every page stays full
+    let fused_ids: Vec<Uuid> = fused.iter().map(|(i,_)|*i).collect();
+    let allergens = match super::allergen::AllergenGuard::load(pg_pool, &user_id, &fused_ids).await {
+        Ok(res) => res,
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"))
+        }
+    };
+    let matched = fused.len();
+    fused.retain(|(m, _)| allergens.allows(m));
+    filtered |= fused.len() < matched;
+
+    Ok((SearchSession::new(user_id, fused, stalls), filtered))
+}
+
 fn stalls_by_frequency(input: &Vec<Uuid>) -> Vec<Uuid> {
     let mut freq_map: HashMap<Uuid, usize> = HashMap::new();
     for &id in input {
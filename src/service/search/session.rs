@@ -0,0 +1,81 @@
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use sqlx::types::Uuid;
+
+use super::super::env_or;
+
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 100;
+
+// A finished ranking, kept so that later pages read from the same order
+#[derive(Debug, Clone)]
+pub struct SearchSession {
+    pub user_id: Uuid,
+    pub hits: Vec<(Uuid, f64)>,     // (menu, relevance), best first
+    pub stalls: Vec<Uuid>,          // Already ordered for display
+    created_at: Instant
+}
+
+impl SearchSession {
+    pub fn new(user_id: Uuid, hits: Vec<(Uuid, f64)>, stalls: Vec<Uuid>) -> Self {
+        Self { user_id, hits, stalls, created_at: Instant::now() }
+    }
+}
+
+// Search sessions by id, dropped once older than the TTL.
+// Bounded by count like the embedding cache, under load the least recently read go first.
+// Lives in memory only, a restarted server sends clients back to the first page.
+#[derive(Debug)]
+pub struct SearchSessions {
+    ttl: Duration,
+    sessions: Mutex<LruCache<Uuid, SearchSession>>
+}
+
+impl SearchSessions {
+    pub fn from_env() -> Self {
+        let capacity = NonZeroUsize::new(env_or("SEARCH_SESSION_CAPACITY", 10000))
+            .unwrap_or(NonZeroUsize::MIN);
+        Self {
+            ttl: Duration::from_secs(env_or("SEARCH_SESSION_TTL_SECS", 300)),
+            sessions: Mutex::new(LruCache::new(capacity))
+        }
+    }
+
+    pub fn insert(&self, session: SearchSession) -> Uuid {
+        let id = Uuid::new_v4();
+        self.sessions.lock().unwrap().put(id, session);
+        id
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<SearchSession> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(id) {
+            Some(s) if s.created_at.elapsed() < self.ttl => Some(s.clone()),
+            Some(_) => {
+                sessions.pop(id);
+                None
+            }
+            None => None
+        }
+    }
+}
+
+// Cursors are "<session id>:<offset of the next hit>"
+pub fn encode_cursor(session_id: &Uuid, offset: usize) -> String {
+    format!("{}:{}", session_id, offset)
+}
+
+pub fn decode_cursor(cursor: &str) -> Option<(Uuid, usize)> {
+    let (session_id, offset) = cursor.split_once(':')?;
+    Some((session_id.parse::<Uuid>().ok()?, offset.parse::<usize>().ok()?))
+}
+
+pub fn page_size(requested: i32) -> usize {
+    match requested {
+        n if n <= 0 => DEFAULT_PAGE_SIZE,
+        n => (n as usize).min(MAX_PAGE_SIZE)
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use sqlx::postgres::PgListener;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use tonic::{Response, Status};
+
+use super::super::backend::{Send, Recv};
+use super::super::env_period;
+use super::super::kueater::data::search::*;
+
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 50;
+
+// Channel raised by the triggers in migration 0016
+const CATALOG_CHANNEL: &str = "kueater_catalog";
+
+#[derive(Debug, sqlx::FromRow)]
+struct SuggestionRow {
+    kind: String,
+    object_id: Option<Uuid>,
+    label: String,
+    popularity: f64
+}
+
+#[derive(Debug)]
+struct Entry {
+    kind: SuggestionKind,
+    object_id: Option<Uuid>,
+    label: String,
+    popularity: f64
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    entries: Vec<Entry>,
+    keys: Vec<(String, usize)>     // (lowercased name from a word start, entry), sorted
+}
+
+// Names of dishes, stalls, cuisines and ingredients, looked up by prefix of any word.
+// Rebuilt from the database as a whole, readers keep the old index meanwhile.
+#[derive(Debug, Default)]
+pub struct SuggestIndex {
+    index: RwLock<Arc<Index>>
+}
+
+fn to_kind(kind: &str) -> Option<SuggestionKind> {
+    match kind {
+        "menu" => Some(SuggestionKind::Menu),
+        "stall" => Some(SuggestionKind::Stall),
+        "cuisine" => Some(SuggestionKind::Cuisine),
+        "ingredient" => Some(SuggestionKind::Ingredient),
+        _ => None
+    }
+}
+
+fn normalize(text: &str) -> String {
+    text.trim().to_lowercase()
+}
+
+impl SuggestIndex {
+    pub async fn rebuild(&self, pg_pool: &PgPool) -> Result<(), sqlx::Error> {
+        let rows: Vec<SuggestionRow> = sqlx::query_as(
+            "SELECT * FROM kueater.search_suggestions()"
+        ).fetch_all(pg_pool).await?;
+
+        let entries: Vec<Entry> = rows.into_iter()
+            .filter_map(|r| Some(Entry {
+                kind: to_kind(&r.kind)?,
+                object_id: r.object_id,
+                label: r.label,
+                popularity: r.popularity
+            }))
+            .collect();
+
+        // "Pad Kraprao Moo" is found by "pad", "krap" and "moo"
+        let mut keys: Vec<(String, usize)> = vec![];
+        for (i, e) in entries.iter().enumerate() {
+            let name = normalize(&e.label);
+            let mut at_word_start = true;
+            for (pos, c) in name.char_indices() {
+                if c.is_whitespace() {
+                    at_word_start = true;
+                } else if at_word_start {
+                    keys.push((name[pos..].to_string(), i));
+                    at_word_start = false;
+                }
+            }
+        }
+        keys.sort();
+
+        *self.index.write().unwrap() = Arc::new(Index { entries, keys });
+        Ok(())
+    }
+
+    fn lookup(&self, prefix: &str, limit: usize) -> Vec<Suggestion> {
+        let index = self.index.read().unwrap().clone();
+
+        let start = index.keys.partition_point(|(k, _)| k.as_str() < prefix);
+        let matched: HashSet<usize> = index.keys[start..].iter()
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(_, i)| *i)
+            .collect();
+
+        let mut matched: Vec<&Entry> = matched.into_iter().map(|i| &index.entries[i]).collect();
+        matched.sort_by(|a, b| b.popularity.total_cmp(&a.popularity).then(a.label.cmp(&b.label)));
+
+        matched.into_iter().take(limit).map(|e| Suggestion {
+            text: e.label.clone(),
+            kind: e.kind.into(),
+            uuid: e.object_id.map(|id| id.to_string())
+        }).collect()
+    }
+}
+
+pub async fn suggest_search(
+    index: &SuggestIndex,
+    request: Recv<SuggestRequest>
+) -> Send<SuggestResponse> {
+
+    let data = request.into_inner();
+
+    let prefix = normalize(&data.prefix);
+    if prefix.is_empty() {
+        return Ok(Response::new(SuggestResponse { suggestions: vec![] }));
+    }
+
+    if prefix.chars().count() > 100 {
+        return Err(Status::invalid_argument("Prefix too long"));
+    }
+
+    let limit = match data.limit {
+        n if n <= 0 => DEFAULT_LIMIT,
+        n => (n as usize).min(MAX_LIMIT)
+    };
+
+    Ok(Response::new(SuggestResponse {
+        suggestions: index.lookup(&prefix, limit)
+    }))
+}
+
+// Rebuild the index when the catalog changes, and on an interval so popularity stays fresh.
+// Without a listener connection it falls back to the interval alone.
+pub async fn run_suggest_index_refresher(pg_pool: PgPool, index: Arc<SuggestIndex>) {
+    let mut interval = tokio::time::interval(env_period("SUGGEST_REFRESH_SECS", 600));
+
+    let mut listener = match PgListener::connect_with(&pg_pool).await {
+        Ok(mut l) => match l.listen(CATALOG_CHANNEL).await {
+            Ok(_) => Some(l),
+            Err(e) => {
+                println!("Cannot listen for catalog changes: {}", e);
+                None
+            }
+        },
+        Err(e) => {
+            println!("Cannot listen for catalog changes: {}", e);
+            None
+        }
+    };
+
+    loop {
+        match listener.as_mut() {
+            Some(l) => {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    notification = l.recv() => {
+                        if let Err(e) = notification {
+                            println!("Catalog listener error: {}", e);
+                        }
+                        // Bulk edits notify once per statement, let them settle and rebuild once
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        while let Ok(Some(_)) = l.try_recv().await {}
+                    }
+                }
+            }
+            None => {
+                interval.tick().await;
+            }
+        }
+
+        if let Err(e) = index.rebuild(&pg_pool).await {
+            println!("Cannot rebuild search suggestions: {}", e);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use futures::{stream, StreamExt};
+use serde::Deserialize;
+use sqlx::types::Uuid;
+use sqlx::{Error, PgPool};
+use tonic::{Response, Status};
+
+use super::backend::{Send, Recv};
+use super::kueater::data::*;
+
+#[derive(Debug, Deserialize, sqlx::FromRow)]
+struct MenuItem {
+    uuid: String,
+    name: String,
+    price: f64,
+    likes: i32,
+    dislikes: i32,
+    stall_id: String,
+    stall_name: String,
+    stall_lock: String,
+    image_url: String,
+    score: Option<f64>,
+    reason: Option<String>,
+    liked: bool,
+    disliked: bool,
+    saved: bool
+}
+
+// "You may also like": nearest neighbours of the item's stored embedding.
+// No agent call, items without an embedding simply have no similar items.
+pub async fn get_similar_items(
+    pg_pool: &PgPool,
+    request: Recv<GetSimilarItemsRequest>
+) -> Send<GetSimilarItemsResponse> {
+    let extensions = request.extensions().clone();
+    let data = request.into_inner();
+
+    let user_id = extensions.get::<super::UserContext>().unwrap().user_id.clone();
+
+    let user_id = match user_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("User id not a UUID"));
+        }
+    };
+
+    let menu_id = match data.item_id.parse::<Uuid>() {
+        Ok(res) => res,
+        Err(_) => {
+            return Err(Status::invalid_argument("Menu id not a UUID"));
+        }
+    };
+
+    let limit = match data.limit {
+        n if n <= 0 => 10,
+        n => n.min(50)
+    };
+
+    let similar: Result<Vec<(Uuid,)>, Error> = sqlx::query_as(
+        "SELECT menu_id FROM kueater.similar_menuitems($1, $2, $3, $4, $5)"
+    )
+    .bind(menu_id)
+    .bind(user_id)
+    .bind(limit)
+    .bind(data.exclude_disliked)
+    .bind(data.exclude_allergens)
+    .fetch_all(pg_pool).await;
+
+    let mut items_to_query: Vec<Uuid> = match similar {
+        Ok(rows) => rows.iter().map(|(i,)|*i).collect(),
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+
+    let allergens = match super::allergen::AllergenGuard::load(pg_pool, &user_id, &items_to_query).await {
+        Ok(res) => res,
+        Err(e) => {
+            println!("{}", e);
+            return Err(Status::internal("Database failure"));
+        }
+    };
+    items_to_query.retain(|id| allergens.allows(id));
+
+    let conversions: Vec<MenuItem> = stream::iter(&items_to_query)
+        .then(|uuid| async move {
+            let item: MenuItem = sqlx::query_as(
+                "SELECT * FROM kueater.get_menu_card_props($1, $2)"
+            )
+            .bind(uuid)
+            .bind(user_id)
+            .fetch_one(pg_pool).await.unwrap();
+            item
+        }).collect().await;
+
+    let explanations = super::explain::explain_items(pg_pool, &user_id, &items_to_query).await;
+
+    Ok(Response::new(GetSimilarItemsResponse {
+        menus: conversions.iter().map(|i| types::MenuCardProps {
+            uuid: i.uuid.clone(),
+            name: i.name.clone(),
+            price: i.price,
+            likes: i.likes,
+            dislikes: i.dislikes,
+            stall_id: i.stall_id.clone(),
+            stall_name: i.stall_name.clone(),
+            stall_lock: i.stall_lock.clone(),
+            image_url: i.image_url.clone(),
+            score: i.score.map(|v| v as f32),
+            reason: i.reason.clone(),
+            liked: i.liked,
+            disliked: i.disliked,
+            saved: i.saved,
+            explanation: explanations.get(&i.uuid).cloned(),
+            allergen_warning: allergens.warning(&i.uuid)
+        }).collect()
+    }))
+}
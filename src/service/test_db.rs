@@ -0,0 +1,32 @@
+// Fixtures for tests of the SQL behind the handlers. They need a migrated database in
+// DATABASE_URL, so they are ignored by default: cargo test -- --ignored
+use sqlx::types::Uuid;
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+
+// Everything a test writes is rolled back when the transaction is dropped
+pub async fn begin() -> Transaction<'static, Postgres> {
+    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL unset");
+    let pool = PgPool::connect(&url).await.expect("Cannot connect to the database");
+    pool.begin().await.expect("Cannot start a transaction")
+}
+
+pub async fn user(conn: &mut PgConnection) -> Uuid {
+    let id = Uuid::new_v4();
+    sqlx::query("INSERT INTO kueater.userprofile (id, name) VALUES ($1, 'test')")
+        .bind(id)
+        .execute(conn).await.unwrap();
+    id
+}
+
+// Menu items named "0", "1", ... with ids in the same order
+pub async fn menu_items(conn: &mut PgConnection, count: usize) -> Vec<Uuid> {
+    let base = Uuid::new_v4().as_u128() & !0xffff;
+    let ids: Vec<Uuid> = (0..count).map(|i| Uuid::from_u128(base + i as u128)).collect();
+    for (i, id) in ids.iter().enumerate() {
+        sqlx::query("INSERT INTO kueater.menuitem (id, name, price) VALUES ($1, $2, 10)")
+            .bind(id)
+            .bind(i.to_string())
+            .execute(&mut *conn).await.unwrap();
+    }
+    ids
+}